@@ -0,0 +1,102 @@
+extern crate tcp_service_lib;
+extern crate byteorder;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, BigEndian};
+use tcp_service_lib::{MessageHandler, Config, Mode, Bytes};
+use tcp_service_lib::errors::*;
+
+const WARMUP: usize = 1000;
+const REQUESTS: usize = 20000;
+
+struct Echo{}
+
+impl MessageHandler for Echo {
+    type Req = Bytes;
+    type Resp = Bytes;
+
+    fn process(&self, msg: Bytes) -> Result<Bytes> {
+        Ok(msg)
+    }
+
+    fn serialize(&self, msg: Bytes) -> Result<Bytes> {
+        Ok(msg)
+    }
+
+    fn deserialize(&self, buf: Bytes) -> Result<Bytes> {
+        Ok(buf)
+    }
+}
+
+static HANDLER: Echo = Echo{};
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    for _ in 0..50 {
+        if let Ok(sock) = TcpStream::connect(addr) {
+            sock.set_nodelay(true).expect("couldn't set nodelay");
+            return sock;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("couldn't connect to {}", addr);
+}
+
+// One request in flight at a time, so every sample is a full round trip through the server.
+fn client(addr: SocketAddr) -> Vec<Duration> {
+    let mut sock = connect(addr);
+    let body = b"ping";
+    let mut request = vec![0u8; 8];
+    BigEndian::write_u64(&mut request, body.len() as u64);
+    request.extend_from_slice(body);
+    let mut reply = vec![0u8; request.len()];
+    let mut samples = Vec::with_capacity(REQUESTS);
+
+    for i in 0..WARMUP + REQUESTS {
+        let start = Instant::now();
+        sock.write_all(&request).expect("couldn't write request");
+        sock.read_exact(&mut reply).expect("couldn't read reply");
+        if i >= WARMUP {
+            samples.push(start.elapsed());
+        }
+    }
+    samples
+}
+
+fn percentile(samples: &[Duration], p: f64) -> f64 {
+    let idx = ((samples.len() - 1) as f64 * p) as usize;
+    samples[idx].as_secs_f64() * 1e6
+}
+
+fn run(name: &str, addr: SocketAddr, config: Config, clients: usize) {
+    let sd = tcp_service_lib::bootstrap_with_config(addr, config, &HANDLER).expect("couldn't start server");
+    let start = Instant::now();
+
+    let handles: Vec<_> = (0..clients).map(|_| thread::spawn(move || client(addr))).collect();
+    let mut samples = vec!{};
+    for handle in handles {
+        samples.extend(handle.join().expect("client failed"));
+    }
+    let elapsed = start.elapsed();
+    samples.sort();
+
+    println!("{:<28} p50 {:>7.1}us  p99 {:>7.1}us  p99.9 {:>7.1}us  {:>8.0} req/s", name,
+        percentile(&samples, 0.5), percentile(&samples, 0.99), percentile(&samples, 0.999),
+        samples.len() as f64 / elapsed.as_secs_f64());
+    sd.shutdown().expect("couldn't shut down");
+}
+
+fn main() {
+    let run_to_completion = Config {
+        mode: Mode::RunToCompletion,
+        ..Config::default()
+    };
+
+    run("worker pool, 1 client", "127.0.0.1:7951".parse().unwrap(), Config::default(), 1);
+    run("worker pool, 8 clients", "127.0.0.1:7952".parse().unwrap(), Config::default(), 8);
+    run("run to completion, 1 client", "127.0.0.1:7953".parse().unwrap(), run_to_completion.clone(), 1);
+    run("run to completion, 8 clients", "127.0.0.1:7954".parse().unwrap(), run_to_completion, 8);
+}
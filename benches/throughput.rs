@@ -0,0 +1,161 @@
+extern crate tcp_service_lib;
+extern crate byteorder;
+
+use std::fs;
+use std::io::{self, IoSlice, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, BigEndian};
+use tcp_service_lib::{MessageHandler, Config, Bytes};
+use tcp_service_lib::errors::*;
+
+const CLIENTS: usize = 4;
+const BATCHES: usize = 500;
+const BATCH_SIZE: usize = 32;
+
+struct Echo{}
+
+impl MessageHandler for Echo {
+    type Req = Bytes;
+    type Resp = Bytes;
+
+    fn process(&self, msg: Bytes) -> Result<Bytes> {
+        Ok(msg)
+    }
+
+    fn serialize(&self, msg: Bytes) -> Result<Bytes> {
+        Ok(msg)
+    }
+
+    fn deserialize(&self, buf: Bytes) -> Result<Bytes> {
+        Ok(buf)
+    }
+}
+
+static HANDLER: Echo = Echo{};
+
+// write(2) and writev(2) calls made by this process as counted by the kernel. The server's
+// write path uses writev while the std client sockets use send(2), so only the server is counted.
+fn write_syscalls() -> u64 {
+    let io = fs::read_to_string("/proc/self/io").unwrap_or_default();
+    io.lines()
+        .find(|line| line.starts_with("syscw:"))
+        .and_then(|line| line["syscw:".len()..].trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    for _ in 0..50 {
+        if let Ok(sock) = TcpStream::connect(addr) {
+            return sock;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("couldn't connect to {}", addr);
+}
+
+// Each client writes a batch of requests in one go and then reads all the replies, so the
+// server sees many queued responses per connection.
+fn client(addr: SocketAddr) {
+    let mut sock = connect(addr);
+    let mut batch = Vec::new();
+    for i in 0..BATCH_SIZE {
+        let body = format!("request {}", i);
+        let mut len_buf = [0u8; 8];
+        BigEndian::write_u64(&mut len_buf, body.len() as u64);
+        batch.extend_from_slice(&len_buf);
+        batch.extend_from_slice(body.as_bytes());
+    }
+    let mut replies = vec![0u8; batch.len()];
+
+    for _ in 0..BATCHES {
+        sock.write_all(&batch).expect("couldn't write batch");
+        sock.read_exact(&mut replies).expect("couldn't read replies");
+    }
+}
+
+fn run(name: &str, addr: SocketAddr, config: Config) {
+    let sd = tcp_service_lib::bootstrap_with_config(addr, config, &HANDLER).expect("couldn't start server");
+    let syscalls = write_syscalls();
+    let start = Instant::now();
+
+    let clients: Vec<_> = (0..CLIENTS).map(|_| thread::spawn(move || client(addr))).collect();
+    for client in clients {
+        client.join().expect("client failed");
+    }
+
+    let elapsed = start.elapsed();
+    let requests = (CLIENTS * BATCHES * BATCH_SIZE) as f64;
+    let syscalls = write_syscalls() - syscalls;
+    println!("{:<20} {:>10.0} req/s {:>8.3} write syscalls/req", name, requests / elapsed.as_secs_f64(),
+        syscalls as f64 / requests);
+    sd.shutdown().expect("couldn't shut down");
+}
+
+// Writes every message with one call for its length prefix and one for its body, as the
+// write path did before it coalesced. Returns the number of write calls.
+fn write_each(sock: &mut TcpStream, prefix: &[u8], body: &[u8]) -> io::Result<u64> {
+    let mut calls = 0;
+    for _ in 0..BATCH_SIZE {
+        for part in &[prefix, body] {
+            let mut written = 0;
+            while written < part.len() {
+                written += sock.write(&part[written..])?;
+                calls += 1;
+            }
+        }
+    }
+    Ok(calls)
+}
+
+// Gathers every prefix and body of a batch into writev calls, as the write path does now.
+fn write_gathered(sock: &mut TcpStream, prefix: &[u8], body: &[u8]) -> io::Result<u64> {
+    let mut parts = Vec::with_capacity(2 * BATCH_SIZE);
+    for _ in 0..BATCH_SIZE {
+        parts.push(IoSlice::new(prefix));
+        parts.push(IoSlice::new(body));
+    }
+    let mut bufs = &mut parts[..];
+    let mut calls = 0;
+    while !bufs.is_empty() {
+        let n = sock.write_vectored(bufs)?;
+        calls += 1;
+        IoSlice::advance_slices(&mut bufs, n);
+    }
+    Ok(calls)
+}
+
+// The write path before and after coalescing, compared on its own over one loopback
+// connection whose reader discards everything.
+fn compare_write_paths(name: &str, write: fn(&mut TcpStream, &[u8], &[u8]) -> io::Result<u64>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("couldn't bind");
+    let mut sock = TcpStream::connect(listener.local_addr().expect("no local address")).expect("couldn't connect");
+    sock.set_nodelay(true).expect("couldn't set nodelay");
+    let (mut peer, _) = listener.accept().expect("couldn't accept");
+
+    let body = b"response body";
+    let mut prefix = [0u8; 8];
+    BigEndian::write_u64(&mut prefix, body.len() as u64);
+    let total = (CLIENTS * BATCHES * BATCH_SIZE * (prefix.len() + body.len())) as u64;
+    let reader = thread::spawn(move || io::copy(&mut (&mut peer).take(total), &mut io::sink()));
+
+    let start = Instant::now();
+    let mut calls = 0;
+    for _ in 0..CLIENTS * BATCHES {
+        calls += write(&mut sock, &prefix, body).expect("couldn't write");
+    }
+    assert_eq!(reader.join().expect("reader failed").expect("couldn't read"), total);
+
+    let elapsed = start.elapsed();
+    let messages = (CLIENTS * BATCHES * BATCH_SIZE) as f64;
+    println!("{:<20} {:>10.0} msg/s {:>8.3} write syscalls/msg", name, messages / elapsed.as_secs_f64(),
+        calls as f64 / messages);
+}
+
+fn main() {
+    compare_write_paths("before: write each", write_each);
+    compare_write_paths("after: writev", write_gathered);
+    run("worker pool", "127.0.0.1:7950".parse().unwrap(), Config::default());
+}
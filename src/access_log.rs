@@ -0,0 +1,346 @@
+use std::fmt;
+use std::fmt::Write as FmtWrite;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ::Config;
+use connection::ConnId;
+use metrics::Failure;
+use errors::*;
+
+// Records beyond this many waiting on the writer are dropped and counted, a slow disk must
+// never hold up the threads serving requests.
+const QUEUED_RECORDS: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogTarget {
+    Stdout,
+    Stderr,
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    pub target: LogTarget,
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Deserialize,
+    Process,
+    Serialize,
+}
+
+// Time one request spent in each stage. Streaming handlers serialize from inside process, so
+// there process includes serialize.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stages {
+    pub queue: Duration,
+    pub deserialize: Duration,
+    pub process: Duration,
+    pub serialize: Duration,
+}
+
+impl Stages {
+    pub fn add(&mut self, stage: Stage, elapsed: Duration) {
+        match stage {
+            Stage::Deserialize => self.deserialize += elapsed,
+            Stage::Process => self.process += elapsed,
+            Stage::Serialize => self.serialize += elapsed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Failed(Failure),
+    // the connection or the write pipeline went away before the response was queued
+    Closed,
+}
+
+impl Outcome {
+    pub fn name(self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::Failed(failure) => failure.name(),
+            Outcome::Closed => "closed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RequestRecord {
+    pub time: SystemTime,
+    pub conn: ConnId,
+    pub peer: Option<SocketAddr>,
+    pub request_bytes: usize,
+    pub response_bytes: usize,
+    // from the I/O thread handing the request off until the last response was queued
+    pub latency: Duration,
+    pub stages: Stages,
+    pub outcome: Outcome,
+}
+
+// Handle held by every processor. Lines are formatted and written by one background thread
+// per log, which exits once every handle is gone.
+#[derive(Clone)]
+pub struct RequestLog {
+    access: Option<LogWriter>,
+    slow: Option<LogWriter>,
+    slow_threshold: Duration,
+}
+
+impl RequestLog {
+    pub fn start(config: &Config) -> Result<Option<RequestLog>> {
+        if config.access_log.is_none() && config.slow_log.is_none() {
+            return Ok(None);
+        }
+
+        let access = match config.access_log {
+            Some(ref log) => Some(LogWriter::spawn(log, false)?),
+            None => None,
+        };
+        let slow = match config.slow_log {
+            Some(ref log) => Some(LogWriter::spawn(log, true)?),
+            None => None,
+        };
+
+        Ok(Some(RequestLog {
+            access,
+            slow,
+            slow_threshold: config.slow_threshold,
+        }))
+    }
+
+    pub fn record(&self, record: RequestRecord) {
+        if let Some(ref access) = self.access {
+            access.send(record);
+        }
+        if let Some(ref slow) = self.slow {
+            if record.latency >= self.slow_threshold {
+                slow.send(record);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct LogWriter {
+    tx: SyncSender<RequestRecord>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl LogWriter {
+    fn spawn(config: &LogConfig, stages: bool) -> Result<LogWriter> {
+        let out: Box<dyn Write + Send> = match config.target {
+            LogTarget::Stdout => Box::new(io::stdout()),
+            LogTarget::Stderr => Box::new(io::stderr()),
+            LogTarget::File(ref path) => Box::new(OpenOptions::new().create(true).append(true).open(path)
+                .chain_err(|| format!("unable to open request log {}", path.display()))?),
+        };
+
+        let (tx, rx) = mpsc::sync_channel(QUEUED_RECORDS);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer_dropped = dropped.clone();
+        let format = config.format;
+
+        thread::spawn(move || {
+            write_records(rx, BufWriter::new(out), format, stages, &writer_dropped);
+        });
+
+        Ok(LogWriter {
+            tx,
+            dropped,
+        })
+    }
+
+    fn send(&self, record: RequestRecord) {
+        match self.tx.try_send(record) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            },
+            Err(TrySendError::Disconnected(_)) => {},
+        }
+    }
+}
+
+// Blocks for the next record and then writes whatever else queued up meanwhile, so a busy
+// server flushes once per batch rather than once per line.
+fn write_records<W: Write>(rx: Receiver<RequestRecord>, mut out: W, format: LogFormat, stages: bool,
+        dropped: &AtomicU64) {
+    let mut line = String::with_capacity(256);
+
+    while let Ok(record) = rx.recv() {
+        let mut next = Some(record);
+        while let Some(record) = next {
+            line.clear();
+            let res = match format {
+                LogFormat::Text => text_line(&mut line, &record, stages),
+                LogFormat::Json => json_line(&mut line, &record, stages),
+            };
+            if res.is_ok() {
+                if let Err(e) = out.write_all(line.as_bytes()) {
+                    warn!("unable to write request log, {:?}", e);
+                }
+            }
+            next = rx.try_recv().ok();
+        }
+
+        let lost = dropped.swap(0, Ordering::Relaxed);
+        if lost > 0 {
+            warn!("request log writer fell behind, dropped {} records", lost);
+        }
+        if let Err(e) = out.flush() {
+            warn!("unable to flush request log, {:?}", e);
+        }
+    }
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + u64::from(d.subsec_micros())
+}
+
+fn text_line(out: &mut String, record: &RequestRecord, stages: bool) -> fmt::Result {
+    timestamp(out, record.time)?;
+    write!(out, " conn={}", record.conn)?;
+    match record.peer {
+        Some(peer) => write!(out, " peer={}", peer)?,
+        None => out.push_str(" peer=-"),
+    }
+    write!(out, " req_bytes={} resp_bytes={} latency_us={} outcome={}", record.request_bytes,
+        record.response_bytes, micros(record.latency), record.outcome.name())?;
+    if stages {
+        let s = &record.stages;
+        write!(out, " queue_us={} deserialize_us={} process_us={} serialize_us={}", micros(s.queue),
+            micros(s.deserialize), micros(s.process), micros(s.serialize))?;
+    }
+    out.push('\n');
+    Ok(())
+}
+
+// Every value is a number, a timestamp, an address or a fixed name, none of which need
+// escaping.
+fn json_line(out: &mut String, record: &RequestRecord, stages: bool) -> fmt::Result {
+    out.push_str("{\"time\":\"");
+    timestamp(out, record.time)?;
+    write!(out, "\",\"conn\":\"{}\"", record.conn)?;
+    match record.peer {
+        Some(peer) => write!(out, ",\"peer\":\"{}\"", peer)?,
+        None => out.push_str(",\"peer\":null"),
+    }
+    write!(out, ",\"request_bytes\":{},\"response_bytes\":{},\"latency_us\":{},\"outcome\":\"{}\"",
+        record.request_bytes, record.response_bytes, micros(record.latency), record.outcome.name())?;
+    if stages {
+        let s = &record.stages;
+        write!(out, ",\"stages\":{{\"queue_us\":{},\"deserialize_us\":{},\"process_us\":{},\"serialize_us\":{}}}",
+            micros(s.queue), micros(s.deserialize), micros(s.process), micros(s.serialize))?;
+    }
+    out.push_str("}\n");
+    Ok(())
+}
+
+// RFC 3339 in UTC with milliseconds, e.g. 2026-10-18T09:15:02.031Z.
+fn timestamp(out: &mut String, time: SystemTime) -> fmt::Result {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_date(secs / 86_400);
+    let secs_of_day = secs % 86_400;
+    write!(out, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, secs_of_day / 3600,
+        secs_of_day / 60 % 60, secs_of_day % 60, since_epoch.subsec_millis())
+}
+
+// Days since 1970-01-01 to a proleptic Gregorian date, from Howard Hinnant's civil_from_days.
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU64;
+    use std::sync::mpsc;
+    use std::time::{Duration, UNIX_EPOCH};
+    use connection::ConnId;
+    use metrics::Failure;
+    use super::{RequestRecord, Stages, Outcome, LogFormat, civil_date, write_records};
+
+    fn record() -> RequestRecord {
+        RequestRecord {
+            time: UNIX_EPOCH + Duration::from_millis(1_791_105_302_031),
+            conn: ConnId::new(0, 3, 7),
+            peer: "127.0.0.1:5555".parse().ok(),
+            request_bytes: 12,
+            response_bytes: 40,
+            latency: Duration::from_micros(1500),
+            stages: Stages {
+                queue: Duration::from_micros(200),
+                deserialize: Duration::from_micros(10),
+                process: Duration::from_micros(1200),
+                serialize: Duration::from_micros(20),
+            },
+            outcome: Outcome::Ok,
+        }
+    }
+
+    fn written(format: LogFormat, stages: bool, records: Vec<RequestRecord>) -> String {
+        let (tx, rx) = mpsc::sync_channel(records.len());
+        for record in records {
+            tx.send(record).expect("couldn't queue record");
+        }
+        drop(tx);
+        let mut out = vec!{};
+        write_records(rx, &mut out, format, stages, &AtomicU64::new(0));
+        String::from_utf8(out).expect("not utf8")
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(11_016), (2000, 2, 29));
+        assert_eq!(civil_date(20_744), (2026, 10, 18));
+    }
+
+    #[test]
+    fn text_and_json_lines() {
+        let mut failed = record();
+        failed.peer = None;
+        failed.outcome = Outcome::Failed(Failure::Deserialize);
+
+        assert_eq!(written(LogFormat::Text, false, vec![record(), failed]),
+            "2026-10-04T09:15:02.031Z conn=0/3/7 peer=127.0.0.1:5555 req_bytes=12 resp_bytes=40 latency_us=1500 outcome=ok\n\
+             2026-10-04T09:15:02.031Z conn=0/3/7 peer=- req_bytes=12 resp_bytes=40 latency_us=1500 outcome=deserialize\n");
+
+        assert_eq!(written(LogFormat::Text, true, vec![record()]),
+            "2026-10-04T09:15:02.031Z conn=0/3/7 peer=127.0.0.1:5555 req_bytes=12 resp_bytes=40 latency_us=1500 outcome=ok \
+             queue_us=200 deserialize_us=10 process_us=1200 serialize_us=20\n");
+
+        assert_eq!(written(LogFormat::Json, true, vec![failed]),
+            "{\"time\":\"2026-10-04T09:15:02.031Z\",\"conn\":\"0/3/7\",\"peer\":null,\"request_bytes\":12,\
+             \"response_bytes\":40,\"latency_us\":1500,\"outcome\":\"deserialize\",\"stages\":{\"queue_us\":200,\
+             \"deserialize_us\":10,\"process_us\":1200,\"serialize_us\":20}}\n");
+    }
+}
@@ -0,0 +1,490 @@
+use std::fmt::Write as FmtWrite;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::net::{self, IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use mio::{Poll, Events, Token, PollOpt, Ready, Registration};
+use mio::net::TcpListener;
+
+use metrics::{Metrics, Stats, HistogramSnapshot};
+use connection::{ConnId, ConnectionSnapshot};
+use worker::{MessageSink, Outbound};
+use errors::*;
+
+const LISTENER: Token = Token(0);
+const SHUTDOWN: Token = Token(1);
+
+const MAX_REQUEST: usize = 8 * 1024;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+const PREFIX: &str = "tcp_service";
+
+// Liveness of the threads started by bootstrap. Counts start at the number of threads and go
+// down as they exit, so there is no window at startup where the server looks unhealthy.
+#[derive(Debug)]
+pub struct Health {
+    workers: usize,
+    live_workers: AtomicUsize,
+    io_threads: usize,
+    live_io_threads: AtomicUsize,
+    draining: AtomicBool,
+}
+
+impl Health {
+    pub fn new(workers: usize, io_threads: usize) -> Health {
+        Health {
+            workers,
+            live_workers: AtomicUsize::new(workers),
+            io_threads,
+            live_io_threads: AtomicUsize::new(io_threads),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.live_workers.load(Ordering::Acquire) == self.workers
+            && self.live_io_threads.load(Ordering::Acquire) == self.io_threads
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+}
+
+// Held by a worker or I/O thread for as long as it runs, including while unwinding.
+pub struct Alive {
+    health: Arc<Health>,
+    worker: bool,
+}
+
+impl Alive {
+    pub fn worker(health: Arc<Health>) -> Alive {
+        Alive {
+            health,
+            worker: true,
+        }
+    }
+
+    pub fn io(health: Arc<Health>) -> Alive {
+        Alive {
+            health,
+            worker: false,
+        }
+    }
+}
+
+impl Drop for Alive {
+    fn drop(&mut self) {
+        let live = if self.worker { &self.health.live_workers } else { &self.health.live_io_threads };
+        live.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// Asks the I/O threads about the connections they own. Commands go down the write pipeline
+// so the connections are only ever touched by their own thread.
+#[derive(Debug, Clone)]
+pub struct Connections {
+    sink: MessageSink,
+}
+
+impl Connections {
+    pub fn new(sink: MessageSink) -> Connections {
+        Connections {
+            sink,
+        }
+    }
+
+    pub fn list(&self) -> Result<Vec<ConnectionSnapshot>> {
+        let (tx, rx) = mpsc::channel();
+        self.sink.send(Outbound::ListConnections(tx))?;
+
+        let mut conns = vec!{};
+        for _ in 0..self.sink.io_threads() {
+            conns.extend(rx.recv_timeout(REPLY_TIMEOUT).chain_err(|| "I/O thread didn't list its connections")?);
+        }
+        conns.sort_by_key(|conn| (conn.id.io(), conn.id.idx()));
+        Ok(conns)
+    }
+
+    // Returns false if the connection was already gone, or never belonged to an I/O thread.
+    pub fn close(&self, id: ConnId) -> Result<bool> {
+        if id.io() >= self.sink.io_threads() {
+            return Ok(false);
+        }
+        let (tx, rx) = mpsc::channel();
+        self.sink.send(Outbound::Close(id, tx))?;
+        rx.recv_timeout(REPLY_TIMEOUT).chain_err(|| "I/O thread didn't close the connection")
+    }
+
+    // Returns how many connections were closed.
+    pub fn close_peer(&self, ip: IpAddr) -> Result<usize> {
+        let (tx, rx) = mpsc::channel();
+        self.sink.send(Outbound::ClosePeer(ip, tx))?;
+
+        let mut closed = 0;
+        for _ in 0..self.sink.io_threads() {
+            closed += rx.recv_timeout(REPLY_TIMEOUT).chain_err(|| "I/O thread didn't close connections")?;
+        }
+        Ok(closed)
+    }
+}
+
+// Plain HTTP/1.1 on its own thread, one request per connection. It only ever sees scrapes and
+// health checks, so requests are read with blocking sockets.
+pub struct Admin {
+    sock: TcpListener,
+    stop: Registration,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    conns: Connections,
+}
+
+impl Admin {
+    pub fn new(sock: TcpListener, stop: Registration, metrics: Arc<Metrics>, health: Arc<Health>,
+            conns: Connections) -> Admin {
+        Admin {
+            sock,
+            stop,
+            metrics,
+            health,
+            conns,
+        }
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        let poll = Poll::new()?;
+        let mut events = Events::with_capacity(16);
+        poll.register(&self.sock, LISTENER, Ready::readable(), PollOpt::edge())?;
+        poll.register(&self.stop, SHUTDOWN, Ready::readable(), PollOpt::edge())?;
+
+        loop {
+            poll.poll(&mut events, None)?;
+
+            for evt in events.iter() {
+                if evt.token() == SHUTDOWN {
+                    info!("admin listener shutting down");
+                    return Ok(());
+                }
+                self.accept();
+            }
+        }
+    }
+
+    fn accept(&self) {
+        loop {
+            let sock = match self.sock.accept_std() {
+                Ok((sock, _)) => sock,
+                Err(e) => {
+                    if e.kind() != ErrorKind::WouldBlock {
+                        error!("failed to accept admin connection, {:?}", e);
+                    }
+                    return;
+                }
+            };
+
+            if let Err(e) = self.serve(sock) {
+                warn!("failed to serve admin request, {:?}", e);
+            }
+        }
+    }
+
+    fn serve(&self, mut sock: net::TcpStream) -> Result<()> {
+        sock.set_nonblocking(false)?;
+        sock.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+        let (method, target) = match read_request(&mut sock)? {
+            Some(req) => req,
+            None => return respond(&mut sock, "400 Bad Request", "text/plain", "bad request\n"),
+        };
+        debug!("admin request {} {}", method, target);
+
+        let mut parts = target.splitn(2, '?');
+        let path = parts.next().unwrap_or("");
+        let query = parts.next().unwrap_or("");
+        match (method.as_str(), path) {
+            ("POST", "/connections/close") => self.close(&mut sock, query),
+            ("POST", _) => respond(&mut sock, "405 Method Not Allowed", "text/plain", "method not allowed\n"),
+            (_, "/connections") => match self.conns.list() {
+                Ok(conns) => {
+                    let body = connection_table(&conns)?;
+                    respond(&mut sock, "200 OK", "text/plain", &body)
+                },
+                Err(e) => unavailable(&mut sock, &e),
+            },
+            (_, "/metrics") => {
+                let body = prometheus(&self.metrics.stats(), &self.health)?;
+                respond(&mut sock, "200 OK", "text/plain; version=0.0.4", &body)
+            },
+            (_, "/healthz") => {
+                if self.health.is_alive() {
+                    respond(&mut sock, "200 OK", "text/plain", "ok\n")
+                } else {
+                    respond(&mut sock, "503 Service Unavailable", "text/plain", "threads exited\n")
+                }
+            },
+            (_, "/readyz") => {
+                if !self.health.is_alive() {
+                    respond(&mut sock, "503 Service Unavailable", "text/plain", "threads exited\n")
+                } else if self.health.is_draining() {
+                    respond(&mut sock, "503 Service Unavailable", "text/plain", "draining\n")
+                } else {
+                    respond(&mut sock, "200 OK", "text/plain", "ready\n")
+                }
+            },
+            _ => respond(&mut sock, "404 Not Found", "text/plain", "not found\n"),
+        }
+    }
+
+    // POST /connections/close?id=io/idx/gen closes one connection, ?peer=ip every connection
+    // from that address.
+    fn close(&self, sock: &mut net::TcpStream, query: &str) -> Result<()> {
+        let mut param = query.splitn(2, '=');
+        match (param.next(), param.next()) {
+            (Some("id"), Some(id)) => {
+                let id = match id.parse::<ConnId>() {
+                    Ok(id) => id,
+                    Err(_) => return respond(sock, "400 Bad Request", "text/plain", "bad connection id\n"),
+                };
+                match self.conns.close(id) {
+                    Ok(true) => respond(sock, "200 OK", "text/plain", "closed 1\n"),
+                    Ok(false) => respond(sock, "404 Not Found", "text/plain", "no such connection\n"),
+                    Err(e) => unavailable(sock, &e),
+                }
+            },
+            (Some("peer"), Some(ip)) => {
+                let ip = match ip.parse::<IpAddr>() {
+                    Ok(ip) => ip,
+                    Err(_) => return respond(sock, "400 Bad Request", "text/plain", "bad peer address\n"),
+                };
+                match self.conns.close_peer(ip) {
+                    Ok(closed) => respond(sock, "200 OK", "text/plain", &format!("closed {}\n", closed)),
+                    Err(e) => unavailable(sock, &e),
+                }
+            },
+            _ => respond(sock, "400 Bad Request", "text/plain", "expected id or peer\n"),
+        }
+    }
+}
+
+pub fn bind(addr: &SocketAddr) -> Result<TcpListener> {
+    Ok(TcpListener::bind(addr)?)
+}
+
+// Reads up to the end of the request headers and returns the method and target of a GET or
+// POST request. Nothing here takes a body.
+fn read_request<R: Read>(sock: &mut R) -> Result<Option<(String, String)>> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST {
+            return Ok(None);
+        }
+        match sock.read(&mut chunk)? {
+            0 => return Ok(None),
+            n => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut parts = head.lines().next().unwrap_or("").split(' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if (method == "GET" || method == "POST")
+            && version.starts_with("HTTP/1.") => Ok(Some((method.to_owned(), target.to_owned()))),
+        _ => Ok(None),
+    }
+}
+
+// The I/O threads didn't answer, usually because the server is shutting down.
+fn unavailable<W: Write>(sock: &mut W, e: &Error) -> Result<()> {
+    warn!("admin request failed, {}", e);
+    respond(sock, "503 Service Unavailable", "text/plain", &format!("{}\n", e))
+}
+
+fn respond<W: Write>(sock: &mut W, status: &str, content_type: &str, body: &str) -> Result<()> {
+    write!(sock, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body)?;
+    sock.flush()?;
+    Ok(())
+}
+
+fn connection_table(conns: &[ConnectionSnapshot]) -> Result<String> {
+    let mut out = String::with_capacity(128 * conns.len());
+    for conn in conns {
+        write!(out, "id={} peer=", conn.id)?;
+        match conn.peer {
+            Some(peer) => write!(out, "{}", peer)?,
+            None => out.push('-'),
+        }
+        write!(out, " age_ms={} bytes_in={} bytes_out={} queued_bytes={} in_flight={} worker=",
+            conn.age.as_millis(), conn.bytes_in, conn.bytes_out, conn.queued_bytes, conn.in_flight)?;
+        match conn.worker {
+            Some(worker) => writeln!(out, "{}", worker)?,
+            None => out.push_str("-\n"),
+        }
+    }
+    Ok(out)
+}
+
+pub fn prometheus(stats: &Stats, health: &Health) -> Result<String> {
+    let mut out = String::with_capacity(8 * 1024);
+
+    let counters = [
+        ("connections_accepted_total", "Connections accepted.", stats.connections_accepted),
+        ("connections_closed_total", "Connections closed.", stats.connections_closed),
+        ("received_bytes_total", "Bytes read from clients.", stats.bytes_in),
+        ("sent_bytes_total", "Bytes written to clients.", stats.bytes_out),
+        ("received_frames_total", "Frames read from clients.", stats.frames_in),
+        ("sent_frames_total", "Frames queued for clients.", stats.frames_out),
+    ];
+    for &(name, help, value) in counters.iter() {
+        header(&mut out, name, help, "counter")?;
+        writeln!(out, "{}_{} {}", PREFIX, name, value)?;
+    }
+
+    header(&mut out, "active_connections", "Connections currently open.", "gauge")?;
+    writeln!(out, "{}_active_connections {}", PREFIX, stats.active_connections)?;
+
+    header(&mut out, "worker_queue_depth", "Requests waiting for each worker.", "gauge")?;
+    for (worker, depth) in stats.worker_queue_depths.iter().enumerate() {
+        writeln!(out, "{}_worker_queue_depth{{worker=\"{}\"}} {}", PREFIX, worker, depth)?;
+    }
+
+    header(&mut out, "errors_total", "Errors by kind.", "counter")?;
+    for &(kind, count) in &stats.errors {
+        writeln!(out, "{}_errors_total{{kind=\"{}\"}} {}", PREFIX, kind, count)?;
+    }
+
+    header(&mut out, "stage_duration_seconds", "Time spent in each stage of handling a request.", "histogram")?;
+    let stages = [("queue_wait", &stats.queue_wait), ("deserialize", &stats.deserialize),
+        ("process", &stats.process), ("serialize", &stats.serialize), ("write", &stats.write)];
+    for &(stage, histogram) in stages.iter() {
+        write_histogram(&mut out, "stage_duration_seconds", stage, histogram)?;
+    }
+
+    header(&mut out, "up", "Whether every worker and I/O thread is running.", "gauge")?;
+    writeln!(out, "{}_up {}", PREFIX, health.is_alive() as u8)?;
+    header(&mut out, "draining", "Whether the server is draining.", "gauge")?;
+    writeln!(out, "{}_draining {}", PREFIX, health.is_draining() as u8)?;
+
+    Ok(out)
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) -> Result<()> {
+    writeln!(out, "# HELP {}_{} {}", PREFIX, name, help)?;
+    writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind)?;
+    Ok(())
+}
+
+fn write_histogram(out: &mut String, name: &str, stage: &str, histogram: &HistogramSnapshot) -> Result<()> {
+    let mut cumulative = 0;
+    for &(bound, count) in &histogram.buckets {
+        cumulative += count;
+        let le = match bound {
+            Some(micros) => format!("{}", micros as f64 / 1e6),
+            None => "+Inf".to_owned(),
+        };
+        writeln!(out, "{}_{}_bucket{{stage=\"{}\",le=\"{}\"}} {}", PREFIX, name, stage, le, cumulative)?;
+    }
+    writeln!(out, "{}_{}_sum{{stage=\"{}\"}} {}", PREFIX, name, stage, histogram.sum.as_secs_f64())?;
+    writeln!(out, "{}_{}_count{{stage=\"{}\"}} {}", PREFIX, name, stage, histogram.count)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::time::Duration;
+    use connection::{ConnId, ConnectionSnapshot};
+    use metrics::{Metrics, Failure};
+    use worker;
+    use super::{Connections, Health, connection_table, prometheus, read_request, unavailable};
+
+    #[test]
+    fn parses_request_path() {
+        let mut req = Cursor::new(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec());
+        assert_eq!(read_request(&mut req).expect("couldn't read"), Some(("GET".to_owned(), "/metrics".to_owned())));
+
+        let mut req = Cursor::new(b"POST /connections/close?id=0/1/2 HTTP/1.1\r\n\r\n".to_vec());
+        assert_eq!(read_request(&mut req).expect("couldn't read"),
+            Some(("POST".to_owned(), "/connections/close?id=0/1/2".to_owned())));
+
+        let mut req = Cursor::new(b"DELETE /metrics HTTP/1.1\r\n\r\n".to_vec());
+        assert_eq!(read_request(&mut req).expect("couldn't read"), None);
+
+        let mut req = Cursor::new(b"GET /metrics HTTP/1.1\r\n".to_vec());
+        assert_eq!(read_request(&mut req).expect("couldn't read"), None);
+    }
+
+    #[test]
+    fn unknown_io_threads_hold_no_connections() {
+        let (_source, sink) = worker::write_pipeline();
+        let conns = Connections::new(sink);
+        assert!(!conns.close(ConnId::new(99, 0, 0)).expect("couldn't close"));
+    }
+
+    #[test]
+    fn unanswered_requests_are_unavailable() {
+        let mut out = Vec::new();
+        unavailable(&mut out, &"I/O thread didn't list its connections".into()).expect("couldn't respond");
+        let text = String::from_utf8(out).expect("not utf-8");
+        assert!(text.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(text.ends_with("\r\n\r\nI/O thread didn't list its connections\n"));
+    }
+
+    #[test]
+    fn exposition_format() {
+        let metrics = Metrics::new(2);
+        metrics.connections_accepted.inc();
+        metrics.worker_queues[1].inc();
+        metrics.error(Failure::Read);
+        metrics.process.record(Duration::from_micros(3));
+        metrics.process.record(Duration::from_micros(300));
+        let health = Health::new(2, 1);
+        health.drain();
+
+        let text = prometheus(&metrics.stats(), &health).expect("couldn't format");
+        let has = |line: &str| text.lines().any(|l| l == line);
+
+        assert!(has("# TYPE tcp_service_connections_accepted_total counter"));
+        assert!(has("tcp_service_connections_accepted_total 1"));
+        assert!(has("tcp_service_worker_queue_depth{worker=\"1\"} 1"));
+        assert!(has("tcp_service_errors_total{kind=\"read\"} 1"));
+        assert!(has("tcp_service_stage_duration_seconds_bucket{stage=\"process\",le=\"0.000002\"} 0"));
+        assert!(has("tcp_service_stage_duration_seconds_bucket{stage=\"process\",le=\"0.000004\"} 1"));
+        assert!(has("tcp_service_stage_duration_seconds_bucket{stage=\"process\",le=\"+Inf\"} 2"));
+        assert!(has("tcp_service_stage_duration_seconds_count{stage=\"process\"} 2"));
+        assert!(has("tcp_service_up 1"));
+        assert!(has("tcp_service_draining 1"));
+    }
+
+    #[test]
+    fn connection_listing() {
+        let conn = ConnectionSnapshot {
+            id: ConnId::new(1, 4, 9),
+            peer: "10.0.0.7:4100".parse().ok(),
+            age: Duration::from_millis(2500),
+            bytes_in: 120,
+            bytes_out: 80,
+            queued_bytes: 16,
+            in_flight: 2,
+            worker: Some(3),
+        };
+        let inline = ConnectionSnapshot {
+            peer: None,
+            worker: None,
+            ..conn.clone()
+        };
+
+        assert_eq!(connection_table(&[conn, inline]).expect("couldn't format"),
+            "id=1/4/9 peer=10.0.0.7:4100 age_ms=2500 bytes_in=120 bytes_out=80 queued_bytes=16 in_flight=2 worker=3\n\
+             id=1/4/9 peer=- age_ms=2500 bytes_in=120 bytes_out=80 queued_bytes=16 in_flight=2 worker=-\n");
+    }
+}
@@ -0,0 +1,111 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{self, Poll};
+use std::time::Instant;
+use bytes::Bytes;
+
+use handler::Dispatch;
+use connection::ConnId;
+use pubsub::Publisher;
+use stream::Responses;
+use worker::{Context, Cancellation, timed};
+use access_log::Stage;
+use errors::*;
+
+pub type ResponseFuture<T> = Pin<Box<dyn Future<Output = Result<T>> + Send>>;
+
+// A handler whose process step returns a future instead of blocking, so a worker thread can
+// have many requests waiting on other services at once. Each future is polled on the worker
+// that read its request, or on the I/O thread when running to completion.
+pub trait AsyncMessageHandler: Sync {
+    type Req;
+    type Resp;
+    fn process(&self, msg: Self::Req, ctx: AsyncContext) -> ResponseFuture<Self::Resp>;
+    fn serialize(&self, msg: Self::Resp) -> Result<Bytes>;
+    fn deserialize(&self, buf: Bytes) -> Result<Self::Req>;
+}
+
+// What Context offers, owned so futures can keep it.
+#[derive(Clone)]
+pub struct AsyncContext {
+    conn: ConnId,
+    publisher: Publisher,
+    cancellation: Cancellation,
+}
+
+impl AsyncContext {
+    pub fn new(ctx: &Context) -> AsyncContext {
+        AsyncContext {
+            conn: ctx.conn(),
+            publisher: ctx.publisher().clone(),
+            cancellation: ctx.cancellation(),
+        }
+    }
+
+    pub fn conn(&self) -> ConnId {
+        self.conn
+    }
+
+    pub fn publisher(&self) -> &Publisher {
+        &self.publisher
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.cancellation.deadline()
+    }
+
+    pub fn cancellation(&self) -> &Cancellation {
+        &self.cancellation
+    }
+}
+
+pub struct Async<H> {
+    handler: Arc<H>,
+}
+
+impl<H> Async<H> {
+    pub fn new(handler: H) -> Async<H> {
+        Async {
+            handler: Arc::new(handler),
+        }
+    }
+}
+
+impl<H> Dispatch for Async<H>
+        where H: AsyncMessageHandler + Send + 'static, H::Resp: 'static {
+    fn dispatch(&self, buf: Bytes, ctx: &Context, responses: &mut Responses) -> Result<()> {
+        let req = timed(ctx, Stage::Deserialize, || self.handler.deserialize(buf))
+            .chain_err(|| "unable to deserialize message")?;
+        let future = self.handler.process(req, AsyncContext::new(ctx));
+        responses.defer(Box::pin(Serialized {
+            handler: self.handler.clone(),
+            future,
+        }));
+        Ok(())
+    }
+}
+
+// Serializes the response once the handler's future is done. Time spent serializing counts
+// as processing.
+struct Serialized<H: AsyncMessageHandler> {
+    handler: Arc<H>,
+    future: ResponseFuture<H::Resp>,
+}
+
+impl<H: AsyncMessageHandler> Future for Serialized<H> {
+    type Output = Result<Bytes>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Result<Bytes>> {
+        match self.future.as_mut().poll(cx) {
+            Poll::Ready(Ok(resp)) => Poll::Ready(self.handler.serialize(resp)
+                .chain_err(|| "unable to serialize response")),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e).chain_err(|| "unable to process message")),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,145 @@
+// Serde backed codecs, each behind the cargo feature of the same name. Any request type that
+// implements DeserializeOwned and response type that implements Serialize works with all of
+// them, and every failure comes back as ErrorKind::Codec.
+
+use std::fmt::Display;
+use bytes::Bytes;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use service::Codec;
+use errors::*;
+
+fn codec_error<E: Display>(codec: &'static str) -> impl Fn(E) -> Error {
+    move |e| ErrorKind::Codec(codec, e.to_string()).into()
+}
+
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl<Req: DeserializeOwned, Resp: Serialize> Codec<Req, Resp> for JsonCodec {
+    fn decode(&self, buf: Bytes) -> Result<Req> {
+        ::serde_json::from_slice(&buf).map_err(codec_error("json"))
+    }
+
+    fn encode(&self, msg: Resp) -> Result<Bytes> {
+        ::serde_json::to_vec(&msg).map(Bytes::from).map_err(codec_error("json"))
+    }
+}
+
+// The same encoding as bincode::serialize, but decoding never reads past the frame or
+// allocates more than the frame could hold, whatever the lengths inside it claim.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl<Req: DeserializeOwned, Resp: Serialize> Codec<Req, Resp> for BincodeCodec {
+    fn decode(&self, buf: Bytes) -> Result<Req> {
+        use bincode::Options;
+        ::bincode::DefaultOptions::new().with_fixint_encoding().with_limit(buf.len() as u64)
+            .deserialize(&buf).map_err(codec_error("bincode"))
+    }
+
+    fn encode(&self, msg: Resp) -> Result<Bytes> {
+        use bincode::Options;
+        ::bincode::DefaultOptions::new().with_fixint_encoding()
+            .serialize(&msg).map(Bytes::from).map_err(codec_error("bincode"))
+    }
+}
+
+// Structs are encoded as maps keyed by field name, which is what most other MessagePack
+// libraries expect.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgpackCodec;
+
+#[cfg(feature = "msgpack")]
+impl<Req: DeserializeOwned, Resp: Serialize> Codec<Req, Resp> for MsgpackCodec {
+    fn decode(&self, buf: Bytes) -> Result<Req> {
+        ::rmp_serde::from_slice(&buf).map_err(codec_error("msgpack"))
+    }
+
+    fn encode(&self, msg: Resp) -> Result<Bytes> {
+        ::rmp_serde::to_vec_named(&msg).map(Bytes::from).map_err(codec_error("msgpack"))
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl<Req: DeserializeOwned, Resp: Serialize> Codec<Req, Resp> for CborCodec {
+    fn decode(&self, buf: Bytes) -> Result<Req> {
+        ::serde_cbor::from_slice(&buf).map_err(codec_error("cbor"))
+    }
+
+    fn encode(&self, msg: Resp) -> Result<Bytes> {
+        ::serde_cbor::to_vec(&msg).map(Bytes::from).map_err(codec_error("cbor"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use bytes::Bytes;
+    use service::Codec;
+    use errors::*;
+
+    type Req = (String, Vec<u32>);
+    type Resp = BTreeMap<String, u64>;
+
+    fn round_trip<C: Codec<Req, Resp> + Codec<Resp, Req>>(codec: C) {
+        let req: Req = ("sum".to_owned(), vec![1, 2, 3]);
+        let buf = Codec::<Resp, Req>::encode(&codec, req.clone()).expect("couldn't encode");
+        let decoded: Req = Codec::<Req, Resp>::decode(&codec, buf).expect("couldn't decode");
+        assert_eq!(decoded, req);
+
+        let mut resp = Resp::new();
+        resp.insert("total".to_owned(), 6);
+        let buf = Codec::<Req, Resp>::encode(&codec, resp.clone()).expect("couldn't encode");
+        let decoded: Resp = Codec::<Resp, Req>::decode(&codec, buf).expect("couldn't decode");
+        assert_eq!(decoded, resp);
+
+        match Codec::<Req, Resp>::decode(&codec, Bytes::from_static(&[0xff; 3])) {
+            Err(Error(ErrorKind::Codec(_, _), _)) => {},
+            other => panic!("expected a codec error, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json() {
+        use super::JsonCodec;
+        round_trip(JsonCodec);
+        let buf = Codec::<Req, (String, u8)>::encode(&JsonCodec, ("a".to_owned(), 1)).expect("couldn't encode");
+        assert_eq!(buf, Bytes::from_static(b"[\"a\",1]"));
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode() {
+        use super::BincodeCodec;
+        round_trip(BincodeCodec);
+
+        // a vector claiming far more elements than the frame holds
+        let mut buf = vec![0u8; 8];
+        buf.extend_from_slice(&[0xff; 8]);
+        assert!(Codec::<Vec<u64>, ()>::decode(&BincodeCodec, Bytes::from(buf)).is_err());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack() {
+        round_trip(super::MsgpackCodec);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor() {
+        round_trip(super::CborCodec);
+    }
+}
@@ -1,4 +1,8 @@
 use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use mio::{Token, Ready, Poll, PollOpt};
 use mio::net::{TcpStream};
@@ -6,209 +10,742 @@ use mio::unix::UnixReady;
 
 use errors::*;
 use std::io::prelude::*;
+use std::io;
 use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
 
 use byteorder::{ByteOrder, BigEndian};
+use bytes::{Buf, Bytes, BytesMut};
+use iovec::IoVec;
 
-pub struct Connection {    
-    pub token: Token,
+use pool::BufferPool;
+use trace::ConnSpan;
 
-    sock: TcpStream,
-    interest: Ready,
-    send_queue: VecDeque<Vec<u8>>,
-    left_to_read: Option<u64>,
-    partial_write: bool,
+// Slab keys are reused as soon as a connection closes, so the generation distinguishes the
+// current occupant of a slot from whoever held it before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnId {
+    io: usize,
+    idx: usize,
+    gen: u64,
 }
 
-impl Connection {
-    pub fn new(sock: TcpStream, token: Token) -> Connection {
-        Connection {
-            token: token,
-            sock: sock,
-            interest: Ready::from(UnixReady::hup()),
-            send_queue: VecDeque::with_capacity(32),
-            left_to_read: None,
-            partial_write: false,
+impl ConnId {
+    pub fn new(io: usize, idx: usize, gen: u64) -> ConnId {
+        ConnId {
+            io,
+            idx,
+            gen,
         }
     }
 
-    pub fn send_message(&mut self, message: Vec<u8>) -> Result<()> {
-        if self.send_queue.is_empty() {
-            self.write_message(message)?;
-        } else {
-            self.send_queue.push_back(message);
+    pub fn io(&self) -> usize {
+        self.io
+    }
+
+    pub fn idx(&self) -> usize {
+        self.idx
+    }
+
+    pub fn gen(&self) -> u64 {
+        self.gen
+    }
+}
+
+impl fmt::Display for ConnId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.io, self.idx, self.gen)
+    }
+}
+
+impl FromStr for ConnId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ConnId> {
+        let mut parts = s.split('/');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(io), Some(idx), Some(gen), None) => {
+                match (io.parse(), idx.parse(), gen.parse()) {
+                    (Ok(io), Ok(idx), Ok(gen)) => Ok(ConnId::new(io, idx, gen)),
+                    _ => Err(format!("invalid connection id {}", s).into()),
+                }
+            },
+            _ => Err(format!("invalid connection id {}, expected io/idx/gen", s).into()),
         }
+    }
+}
 
-        if !self.send_queue.is_empty() && !self.interest.is_writable() {
-            self.interest.insert(Ready::writable());
+// Details of a connection shared with every request read from it, so they don't have to be
+// copied into each one.
+#[derive(Debug)]
+pub struct ConnInfo {
+    pub peer: Option<SocketAddr>,
+    in_flight: AtomicUsize,
+    closed: AtomicBool,
+}
+
+impl ConnInfo {
+    pub fn new(peer: Option<SocketAddr>) -> ConnInfo {
+        ConnInfo {
+            peer,
+            in_flight: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
         }
+    }
 
-        Ok(())
+    pub fn request_started(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
     }
 
-    pub fn handle_read(&mut self) -> Result<(Option<Vec<u8>>)> {
-        let msg_len = match self.read_message_length()? {
-            None => { 
-                return Ok(None); 
-            },
-            Some(n) => n,
-        };
+    pub fn request_finished(&self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
 
-        if msg_len == 0 {
-            return Ok(None);
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+// A connection as seen by Shutdown::connections and the admin port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionSnapshot {
+    pub id: ConnId,
+    pub peer: Option<SocketAddr>,
+    pub age: Duration,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // written to the connection but not yet accepted by the socket
+    pub queued_bytes: usize,
+    // read but not yet handled
+    pub in_flight: usize,
+    // the worker its latest requests went to, None when running to completion
+    pub worker: Option<usize>,
+}
+
+const MAX_IOVECS: usize = 64;
+// fills the unused slots of the iovec array, never handed to the socket
+static IOVEC_FILLER: [u8; 1] = [0];
+
+// A queued message with its length prefix, written out as one unit.
+struct Frame {
+    header: [u8; 8],
+    body: Bytes,
+}
+
+impl Frame {
+    fn new(body: Bytes) -> Frame {
+        let mut header = [0u8; 8];
+        BigEndian::write_u64(&mut header, body.len() as u64);
+        Frame {
+            header,
+            body,
         }
+    }
 
-        let msg_len = msg_len as usize;
-        debug!("Expected message length is {}", msg_len);
+    fn len(&self) -> usize {
+        self.header.len() + self.body.len()
+    }
+
+    fn remaining(&self, offset: usize) -> (&[u8], &[u8]) {
+        if offset < self.header.len() {
+            (&self.header[offset..], &self.body)
+        } else {
+            (&[], &self.body[offset - self.header.len()..])
+        }
+    }
+}
+
+pub trait VectoredWrite {
+    fn write_bufs(&mut self, bufs: &[&IoVec]) -> io::Result<usize>;
+}
+
+impl VectoredWrite for TcpStream {
+    fn write_bufs(&mut self, bufs: &[&IoVec]) -> io::Result<usize> {
+        TcpStream::write_bufs(self, bufs)
+    }
+}
 
-        // Here we allocate and set the length with unsafe code. The risks of this are discussed
-        // at https://stackoverflow.com/a/30979689/329496 and are mitigated as recv_buf is
-        // abandoned below if we don't read msg_leg bytes from the socket
-        let mut recv_buf: Vec<u8> = Vec::with_capacity(msg_len);
-        unsafe { 
-            recv_buf.set_len(msg_len); 
+// Frames waiting to go out on a connection. `offset` is how much of the front frame, length
+// prefix included, has already been written, so a write may stop anywhere in a frame.
+struct WriteQueue {
+    frames: VecDeque<Frame>,
+    offset: usize,
+    write_calls: u64,
+    bytes_written: u64,
+}
+
+impl WriteQueue {
+    fn new() -> WriteQueue {
+        WriteQueue {
+            frames: VecDeque::with_capacity(32),
+            offset: 0,
+            write_calls: 0,
+            bytes_written: 0,
         }
+    }
 
-        // UFCS: resolve "multiple applicable items in scope [E0034]" error
-        let sock_ref = <TcpStream as Read>::by_ref(&mut self.sock);
+    fn push(&mut self, body: Bytes) {
+        self.frames.push_back(Frame::new(body));
+    }
 
-        match sock_ref.take(msg_len as u64).read(&mut recv_buf) {
-            Ok(n) => {
-                debug!("read {} bytes", n);
-                if n < msg_len as usize {
-                    return Err("Did not read enough bytes".into());
-                }
+    fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
 
-                self.left_to_read = None;
+    fn queued_bytes(&self) -> usize {
+        self.frames.iter().map(Frame::len).sum::<usize>() - self.offset
+    }
 
-                Ok(Some(recv_buf.to_vec()))
-            },
-            Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
-                    self.left_to_read = Some(msg_len as u64);
-                    Ok(None)
+    // Keeps writing until the queue is empty or the socket would block. With edge triggered
+    // polling there won't be another writable event unless the socket actually filled up.
+    fn flush<W: VectoredWrite>(&mut self, sock: &mut W) -> Result<()> {
+        while !self.frames.is_empty() {
+            let n = {
+                let filler: &IoVec = IOVEC_FILLER[..].into();
+                let mut iovecs = [filler; MAX_IOVECS];
+                let mut count = 0;
+                let mut offset = self.offset;
+
+                'gather: for frame in &self.frames {
+                    let (header, body) = frame.remaining(offset);
+                    offset = 0;
+                    for slice in [header, body].iter().cloned() {
+                        if count == MAX_IOVECS {
+                            break 'gather;
+                        }
+                        if !slice.is_empty() {
+                            iovecs[count] = slice.into();
+                            count += 1;
+                        }
+                    }
                 }
-                else {
-                    Err(e.into())
+
+                self.write_calls += 1;
+                match sock.write_bufs(&iovecs[..count]) {
+                    Ok(0) => {
+                        return Err("Socket accepted no bytes on write".into());
+                    },
+                    Ok(n) => n,
+                    Err(e) => {
+                        match e.kind() {
+                            ErrorKind::WouldBlock => return Ok(()),
+                            ErrorKind::Interrupted => continue,
+                            _ => return Err(e.into()),
+                        }
+                    },
                 }
-            },
+            };
+
+            debug!("wrote {} bytes", n);
+            self.bytes_written += n as u64;
+            self.advance(n);
+        }
+
+        Ok(())
+    }
+
+    // Drops fully written frames and records how far into the next one the socket got.
+    fn advance(&mut self, mut n: usize) {
+        while n > 0 {
+            let remaining = match self.frames.front() {
+                Some(frame) => frame.len() - self.offset,
+                None => return,
+            };
+
+            if n < remaining {
+                self.offset += n;
+                return;
+            }
+
+            n -= remaining;
+            self.offset = 0;
+            self.frames.pop_front();
         }
     }
-    
-    fn read_message_length(&mut self) -> Result<Option<u64>> {
-        if let Some(n) = self.left_to_read {
-            return Ok(Some(n));
+}
+
+pub const LEN_PREFIX: usize = 8;
+const READ_CHUNK: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadStatus {
+    // the socket reported WouldBlock, nothing is left to read until the next readiness event
+    Drained,
+    // the per event budget ran out first, there may be more frames buffered or on the socket
+    Budget,
+    // the peer closed its end, any frames read before that are still returned
+    Closed,
+}
+
+// Bytes read off the socket that haven't been parsed into frames yet. A partial length prefix
+// or body stays buffered until the rest of it arrives. Complete frames are split off the
+// buffer without copying, and the buffer goes back to the pool whenever it is empty so idle
+// connections don't hold on to memory.
+struct ReadBuffer {
+    buf: Option<BytesMut>,
+    bytes_read: u64,
+}
+
+impl ReadBuffer {
+    fn new() -> ReadBuffer {
+        ReadBuffer {
+            buf: None,
+            bytes_read: 0,
         }
+    }
 
-        let mut buf = [0u8; 8];
+    // Under edge-triggered polling the socket has to be read until it would block, otherwise
+    // whatever is left sits there until the peer happens to send more.
+    fn drain<R: Read>(&mut self, sock: &mut R, pool: &mut BufferPool, budget: usize,
+            msgs: &mut Vec<Bytes>) -> io::Result<ReadStatus> {
+        let res = self.read_frames(sock, pool, budget, msgs);
 
-        let bytes = match self.sock.read(&mut buf) {
-            Ok(n) => n,
-            Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
-                    return Ok(None);
-                } else {
-                    return Err(e.into());
-                }
-            },
+        let empty = match self.buf {
+            Some(ref buf) => buf.is_empty(),
+            None => false,
         };
+        if empty {
+            if let Some(buf) = self.buf.take() {
+                pool.give(buf);
+            }
+        }
 
-        if bytes < 8 {
-            return Err("Invalid message length".into());
+        res
+    }
+
+    fn read_frames<R: Read>(&mut self, sock: &mut R, pool: &mut BufferPool, budget: usize,
+            msgs: &mut Vec<Bytes>) -> io::Result<ReadStatus> {
+        let mut taken = 0;
+
+        loop {
+            while let Some(msg) = self.next_frame() {
+                msgs.push(msg);
+                taken += 1;
+                if taken >= budget {
+                    return Ok(ReadStatus::Budget);
+                }
+            }
+
+            match self.fill(sock, pool) {
+                Ok(0) => return Ok(ReadStatus::Closed),
+                Ok(n) => {
+                    debug!("read {} bytes", n);
+                    self.bytes_read += n as u64;
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(ReadStatus::Drained),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
         }
+    }
+
+    fn fill<R: Read>(&mut self, sock: &mut R, pool: &mut BufferPool) -> io::Result<usize> {
+        let buf = self.buf.get_or_insert_with(|| pool.take());
 
-        let msg_len = BigEndian::read_u64(buf.as_ref());
-        Ok(Some(msg_len))
+        let len = buf.len();
+        buf.resize(len + READ_CHUNK, 0);
+        let res = sock.read(&mut buf[len..]);
+        let n = *res.as_ref().unwrap_or(&0);
+        buf.truncate(len + n);
+        res
+    }
+
+    fn next_frame(&mut self) -> Option<Bytes> {
+        match self.buf {
+            Some(ref mut buf) => next_frame(buf),
+            None => None,
+        }
     }
+}
 
-    pub fn handle_write(&mut self) -> Result<()> {
-        self.send_queue.pop_front()
-            .ok_or("Send queue is empty on write".into())
-            .and_then(|buf|{
-                self.write_message(buf)
-            })?;
+// Splits the next complete frame off the front of buf, skipping empty ones. Shared by both
+// backends so they agree on the framing.
+pub fn next_frame(buf: &mut BytesMut) -> Option<Bytes> {
+    loop {
+        if buf.len() < LEN_PREFIX {
+            return None;
+        }
 
-        if self.send_queue.is_empty() {
+        let msg_len = BigEndian::read_u64(&buf[..LEN_PREFIX]) as usize;
+        if buf.len() - LEN_PREFIX < msg_len {
+            return None;
+        }
+
+        buf.advance(LEN_PREFIX);
+        if msg_len == 0 {
+            debug!("skipping empty message");
+            continue;
+        }
+        debug!("Expected message length is {}", msg_len);
+        return Some(buf.split_to(msg_len).freeze());
+    }
+}
+
+pub struct Connection {    
+    pub token: Token,
+    pub id: ConnId,
+    pub info: Arc<ConnInfo>,
+    pub span: ConnSpan,
+    pub worker: Option<usize>,
+    // on the server's read backlog, waiting for another turn
+    pub deferred: bool,
+    opened: Instant,
+
+    sock: TcpStream,
+    interest: Ready,
+    // what the poller was last told, so an unchanged interest costs no syscall
+    registered: Ready,
+    writes: WriteQueue,
+    reads: ReadBuffer,
+}
+
+impl Connection {
+    pub fn new(sock: TcpStream, id: ConnId, token: Token) -> Connection {
+        let peer = sock.peer_addr().ok();
+        Connection {
+            token,
+            id,
+            info: Arc::new(ConnInfo::new(peer)),
+            span: ConnSpan::new(id, peer),
+            worker: None,
+            deferred: false,
+            opened: Instant::now(),
+            sock,
+            interest: Ready::from(UnixReady::hup()),
+            registered: Ready::empty(),
+            writes: WriteQueue::new(),
+            reads: ReadBuffer::new(),
+        }
+    }
+
+    // Only queues the message, the caller flushes once it has queued everything it has for
+    // this connection so multiple messages go out in a single writev.
+    pub fn send_message(&mut self, message: Bytes) -> Result<()> {
+        self.writes.push(message);
+
+        if !self.interest.is_writable() {
+            self.interest.insert(Ready::writable());
+        }
+
+        Ok(())
+    }
+
+    pub fn read_messages(&mut self, pool: &mut BufferPool, budget: usize, msgs: &mut Vec<Bytes>) -> Result<ReadStatus> {
+        Ok(self.reads.drain(&mut self.sock, pool, budget, msgs)?)
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.reads.bytes_read
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.writes.bytes_written
+    }
+
+    pub fn snapshot(&self) -> ConnectionSnapshot {
+        ConnectionSnapshot {
+            id: self.id,
+            peer: self.info.peer,
+            age: self.opened.elapsed(),
+            bytes_in: self.bytes_read(),
+            bytes_out: self.bytes_written(),
+            queued_bytes: self.writes.queued_bytes(),
+            in_flight: self.info.in_flight(),
+            worker: self.worker,
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writes.flush(&mut self.sock)?;
+
+        if self.writes.is_empty() {
             self.interest.remove(Ready::writable());
         }
 
         Ok(())
     }
 
-    fn write_message(&mut self, buf: Vec<u8>) -> Result<()> {
-        match self.write_message_length(&buf) {
-            Ok(None) => {
-                self.send_queue.push_front(buf);
-                return Ok(());
-            }, 
-            Ok(Some(())) => {
-                ()
-            },
-            Err(e) => {
-                return Err(e.into());
+    // Returns whether the poller had to be told. Edge-triggered registrations stay armed, so
+    // they only need updating when the interest changes.
+    pub fn register(&mut self, poll: &mut Poll, initial: bool) -> Result<bool> {
+        let res = if initial {
+            self.interest.insert(Ready::readable());
+            poll.register(&self.sock, self.token, self.interest, PollOpt::edge())
+        } else if self.interest != self.registered {
+            poll.reregister(&self.sock, self.token, self.interest, PollOpt::edge())
+        } else {
+            return Ok(false);
+        };
+
+        match res {
+            Ok(()) => {
+                self.registered = self.interest;
+                Ok(true)
             },
+            Err(e) => Err(e.into())
         }
+    }
+}
 
-        let len = buf.len();
-        match self.sock.write(&buf) {
-            Ok(n) => {
-                if n < len {
-                    let remaining = buf[n..].to_vec();
-                    self.send_queue.push_front(remaining);
-                    self.partial_write = true;
-                } else {
-                    self.partial_write = false;
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::io;
+    use std::io::{Read, ErrorKind};
+    use std::net;
+    use std::thread;
+    use std::time::Duration;
+    use mio::{Poll, Token};
+    use mio::net::TcpStream;
+    use byteorder::{ByteOrder, BigEndian};
+    use iovec::IoVec;
+    use bytes::Bytes;
+    use pool::BufferPool;
+    use super::{Connection, ConnId, VectoredWrite, WriteQueue, ReadBuffer, ReadStatus};
+
+    // Takes at most `chunk` bytes per write and reports WouldBlock once `budget` is used up.
+    struct Throttled {
+        written: Vec<u8>,
+        chunk: usize,
+        budget: usize,
+    }
+
+    impl VectoredWrite for Throttled {
+        fn write_bufs(&mut self, bufs: &[&IoVec]) -> io::Result<usize> {
+            let limit = cmp::min(self.chunk, self.budget);
+            if limit == 0 {
+                return Err(io::Error::new(ErrorKind::WouldBlock, "throttled"));
+            }
+
+            let mut n = 0;
+            for buf in bufs {
+                let take = cmp::min(buf.len(), limit - n);
+                self.written.extend_from_slice(&buf[..take]);
+                n += take;
+                if n == limit {
+                    break;
                 }
-                Ok(())
-            },
-            Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
-                    self.send_queue.push_front(buf);
-                    self.partial_write = true;
-                    Ok(())
-                } else {
-                    Err(e.into())
+            }
+            self.budget -= n;
+            Ok(n)
+        }
+    }
+
+    // Hands out at most `chunk` bytes per read, then WouldBlock or end of stream once the
+    // data runs out.
+    struct Trickle {
+        data: Vec<u8>,
+        pos: usize,
+        chunk: usize,
+        eof: bool,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos == self.data.len() {
+                if self.eof {
+                    return Ok(0);
                 }
-                
-            },
+                return Err(io::Error::new(ErrorKind::WouldBlock, "no more data"));
+            }
+
+            let n = cmp::min(cmp::min(self.chunk, buf.len()), self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
         }
     }
 
-    fn write_message_length(&mut self, buf: &Vec<u8>) -> Result<Option<()>> {
-        if self.partial_write {
-            return Ok(Some(()));
+    fn framed(bodies: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = vec!{};
+        for body in bodies {
+            let mut len_buf = [0u8; 8];
+            BigEndian::write_u64(&mut len_buf, body.len() as u64);
+            buf.extend_from_slice(&len_buf);
+            buf.extend_from_slice(body);
         }
+        buf
+    }
 
-        let mut len_buf = [0u8; 8];
-        BigEndian::write_u64(&mut len_buf, buf.len() as u64);
+    fn connection() -> (net::TcpStream, Connection) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").expect("couldn't bind");
+        let client = net::TcpStream::connect(listener.local_addr().expect("no local address"))
+            .expect("couldn't connect");
+        let (sock, _) = listener.accept().expect("couldn't accept");
+        let sock = TcpStream::from_stream(sock).expect("couldn't convert socket");
+        (client, Connection::new(sock, ConnId::new(0, 0, 0), Token(0)))
+    }
 
-        let len = len_buf.len();
+    #[test]
+    fn queued_messages_coalesce_into_one_write() {
+        let (mut client, mut conn) = connection();
 
-        match self.sock.write(&len_buf) {
-            Ok(n) => {
-                if n < len {
-                    Err("Failed to write message length".into())
-                } else {
-                    Ok(Some(()))
-                }
-            },
-            Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
-                    Ok(None)
-                } else {
-                    Err(e.into())
-                }
-            },
+        for i in 0..10u8 {
+            conn.send_message(Bytes::from(vec![i; i as usize])).expect("couldn't queue message");
+        }
+        conn.flush().expect("couldn't flush");
+
+        assert_eq!(conn.writes.write_calls, 1);
+        assert!(conn.writes.is_empty());
+        assert!(!conn.interest.is_writable());
+
+        for i in 0..10u8 {
+            let mut len_buf = [0u8; 8];
+            client.read_exact(&mut len_buf).expect("couldn't read length");
+            let mut buf = vec![0u8; BigEndian::read_u64(&len_buf) as usize];
+            client.read_exact(&mut buf).expect("couldn't read message");
+            assert_eq!(buf, vec![i; i as usize]);
         }
     }
 
-    pub fn register(&mut self, poll: &mut Poll, initial: bool) -> Result<()> {
-        if initial {
-            self.interest.insert(Ready::readable());
+    #[test]
+    fn reregisters_only_when_interest_changes() {
+        let (_client, mut conn) = connection();
+        let mut poll = Poll::new().expect("couldn't create poll");
+        assert!(conn.register(&mut poll, true).expect("couldn't register"));
+        assert!(!conn.register(&mut poll, false).expect("couldn't reregister"));
+
+        conn.send_message(Bytes::from_static(b"abc")).expect("couldn't queue message");
+        assert!(conn.register(&mut poll, false).expect("couldn't reregister"));
+        conn.flush().expect("couldn't flush");
+        assert!(conn.register(&mut poll, false).expect("couldn't reregister"));
+        assert!(!conn.register(&mut poll, false).expect("couldn't reregister"));
+    }
+
+    #[test]
+    fn conn_id_round_trips_through_text() {
+        let id = ConnId::new(1, 20, 300);
+        assert_eq!(id.to_string(), "1/20/300");
+        assert_eq!("1/20/300".parse::<ConnId>().expect("couldn't parse"), id);
+        assert!("1/20".parse::<ConnId>().is_err());
+        assert!("1/20/300/4".parse::<ConnId>().is_err());
+        assert!("1/x/300".parse::<ConnId>().is_err());
+    }
+
+    #[test]
+    fn partial_prefix_write_resumes() {
+        let bodies = vec![b"first".to_vec(), b"second".to_vec()];
+        let mut queue = WriteQueue::new();
+        for body in &bodies {
+            queue.push(Bytes::from(body.clone()));
         }
-        match poll.register(&self.sock, self.token, self.interest, PollOpt::edge()) {
-            Ok(()) => Ok(()),
-            Err(e) => Err(e.into())
+
+        // stop five bytes into the first length prefix
+        let mut sock = Throttled { written: vec!{}, chunk: 3, budget: 5 };
+        queue.flush(&mut sock).expect("couldn't flush");
+        assert_eq!(sock.written.len(), 5);
+        assert_eq!(queue.offset, 5);
+        assert_eq!(queue.queued_bytes(), 8 + 5 + 8 + 6 - 5);
+        assert!(!queue.is_empty());
+
+        sock.budget = 1024;
+        queue.flush(&mut sock).expect("couldn't flush");
+        assert!(queue.is_empty());
+        assert_eq!(sock.written, framed(&bodies));
+    }
+
+    #[test]
+    fn flush_drains_whole_queue() {
+        let bodies: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; i as usize * 3]).collect();
+        let mut queue = WriteQueue::new();
+        for body in &bodies {
+            queue.push(Bytes::from(body.clone()));
+        }
+
+        let mut sock = Throttled { written: vec!{}, chunk: 7, budget: usize::MAX };
+        queue.flush(&mut sock).expect("couldn't flush");
+
+        assert!(queue.is_empty());
+        assert_eq!(sock.written, framed(&bodies));
+        assert!(queue.write_calls > 1);
+    }
+
+    #[test]
+    fn slow_reader_receives_every_byte() {
+        let (mut client, mut conn) = connection();
+        let bodies: Vec<Vec<u8>> = (0..64u8).map(|i| vec![i; 64 * 1024 + i as usize]).collect();
+        let expected = framed(&bodies);
+        for body in &bodies {
+            conn.send_message(Bytes::from(body.clone())).expect("couldn't queue message");
+        }
+
+        // far more than the socket buffers hold, so the first flush has to stop part way
+        conn.flush().expect("couldn't flush");
+        assert!(!conn.writes.is_empty());
+
+        let expected_len = expected.len();
+        let reader = thread::spawn(move || {
+            let mut received = vec!{};
+            let mut buf = [0u8; 4096];
+            while received.len() < expected_len {
+                let n = client.read(&mut buf).expect("couldn't read");
+                assert!(n > 0, "connection closed early");
+                received.extend_from_slice(&buf[..n]);
+            }
+            received
+        });
+
+        while !conn.writes.is_empty() {
+            conn.flush().expect("couldn't flush");
+            thread::sleep(Duration::from_millis(1));
         }
+        assert!(!conn.interest.is_writable());
+        assert_eq!(reader.join().expect("reader failed"), expected);
+    }
+
+    #[test]
+    fn split_frames_read_until_would_block() {
+        let bodies: Vec<Vec<u8>> = (1..40u8).map(|i| vec![i; i as usize * 11]).collect();
+        // chunks of 5 split both length prefixes and bodies across reads
+        let mut sock = Trickle { data: framed(&bodies), pos: 0, chunk: 5, eof: false };
+        let mut reads = ReadBuffer::new();
+        let mut pool = BufferPool::new(64, 1);
+        let mut msgs = vec!{};
+
+        let status = reads.drain(&mut sock, &mut pool, usize::MAX, &mut msgs).expect("couldn't read");
+        assert_eq!(status, ReadStatus::Drained);
+        assert_eq!(msgs, bodies);
+        assert_eq!(sock.pos, sock.data.len());
+    }
+
+    #[test]
+    fn budget_leaves_remaining_frames_buffered() {
+        let bodies: Vec<Vec<u8>> = (1..11u8).map(|i| vec![i; 3]).collect();
+        let mut sock = Trickle { data: framed(&bodies), pos: 0, chunk: 1024, eof: false };
+        let mut reads = ReadBuffer::new();
+        let mut pool = BufferPool::new(64, 1);
+        let mut msgs = vec!{};
+
+        let status = reads.drain(&mut sock, &mut pool, 4, &mut msgs).expect("couldn't read");
+        assert_eq!(status, ReadStatus::Budget);
+        assert_eq!(msgs, bodies[..4].to_vec());
+
+        // everything was read off the socket already, the rest has to come from the buffer
+        assert_eq!(sock.pos, sock.data.len());
+        let status = reads.drain(&mut sock, &mut pool, usize::MAX, &mut msgs).expect("couldn't read");
+        assert_eq!(status, ReadStatus::Drained);
+        assert_eq!(msgs, bodies);
+    }
+
+    #[test]
+    fn frames_before_close_are_returned() {
+        let bodies = vec![b"last".to_vec(), vec!{}, b"words".to_vec()];
+        let mut sock = Trickle { data: framed(&bodies), pos: 0, chunk: 3, eof: true };
+        let mut reads = ReadBuffer::new();
+        let mut pool = BufferPool::new(64, 1);
+        let mut msgs = vec!{};
+
+        let status = reads.drain(&mut sock, &mut pool, usize::MAX, &mut msgs).expect("couldn't read");
+        assert_eq!(status, ReadStatus::Closed);
+        assert_eq!(msgs, vec![b"last".to_vec(), b"words".to_vec()]);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,100 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::task::{Wake, Waker};
+use bytes::Bytes;
+use crossbeam_queue::SegQueue;
+use mio::{Ready, SetReadiness};
+
+use worker::WorkerMsg;
+use errors::*;
+
+// An async handler's answer to one request, already serialized.
+pub type Pending = Pin<Box<dyn Future<Output = Result<Bytes>> + Send>>;
+
+// Wakes a task on a worker thread by queueing its key behind the requests already waiting
+// there. There is no I/O reactor or timer here, futures are woken by whatever they wait on.
+struct TaskWaker {
+    key: usize,
+    tx: Sender<WorkerMsg>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        // fails only once the worker is gone, and its tasks with it
+        let _ = self.tx.send(WorkerMsg::Wake(self.key));
+    }
+}
+
+pub fn waker(key: usize, tx: Sender<WorkerMsg>) -> Waker {
+    Waker::from(Arc::new(TaskWaker { key, tx }))
+}
+
+// Wakes a task on an I/O thread running to completion. The key joins the queue of woken
+// tasks and the loop's registration turns readable, so its next poll picks them up.
+struct LoopWaker {
+    key: usize,
+    woken: Arc<SegQueue<usize>>,
+    readiness: SetReadiness,
+}
+
+impl Wake for LoopWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.push(self.key);
+        if let Err(e) = self.readiness.set_readiness(Ready::readable()) {
+            warn!("unable to wake task {}, {:?}", self.key, e);
+        }
+    }
+}
+
+pub fn loop_waker(key: usize, woken: Arc<SegQueue<usize>>, readiness: SetReadiness) -> Waker {
+    Waker::from(Arc::new(LoopWaker { key, woken, readiness }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use crossbeam_queue::SegQueue;
+    use mio::{Events, Poll, PollOpt, Ready, Registration, Token};
+    use worker::WorkerMsg;
+    use super::{waker, loop_waker};
+
+    #[test]
+    fn wakers_queue_the_task() {
+        let (tx, rx) = mpsc::channel();
+        let waker = waker(3, tx);
+        waker.wake_by_ref();
+        waker.wake();
+        for _ in 0..2 {
+            match rx.try_recv() {
+                Ok(WorkerMsg::Wake(3)) => {},
+                other => panic!("expected a wakeup, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn loop_wakers_raise_readiness() {
+        let poll = Poll::new().expect("couldn't create poll");
+        let mut events = Events::with_capacity(4);
+        let (registration, readiness) = Registration::new2();
+        poll.register(&registration, Token(0), Ready::readable(), PollOpt::edge()).expect("couldn't register");
+
+        let woken = Arc::new(SegQueue::new());
+        loop_waker(5, woken.clone(), readiness).wake();
+        assert_eq!(poll.poll(&mut events, Some(Duration::from_secs(1))).expect("couldn't poll"), 1);
+        assert_eq!(woken.pop(), Some(5));
+        assert_eq!(woken.pop(), None);
+    }
+}
@@ -0,0 +1,182 @@
+use std::sync::{Arc, Mutex};
+use bytes::Bytes;
+
+use ::MessageHandler;
+use stream::{StreamingMessageHandler, ResponseSender, Responses};
+use worker::{Context, timed};
+use access_log::Stage;
+use errors::*;
+
+// Type erased view of a handler used by the workers, so they don't need to know whether they
+// are driving a one reply per request MessageHandler or a StreamingMessageHandler.
+pub trait Dispatch: Send + Sync {
+    fn dispatch(&self, buf: Bytes, ctx: &Context, responses: &mut Responses) -> Result<()>;
+}
+
+// Lets handlers be passed by reference or shared through an Arc, so they don't have to be
+// statics.
+impl<H: MessageHandler + ?Sized> MessageHandler for &H {
+    type Req = H::Req;
+    type Resp = H::Resp;
+
+    fn process(&self, msg: H::Req) -> Result<H::Resp> {
+        (**self).process(msg)
+    }
+
+    fn process_with_context(&self, msg: H::Req, ctx: &Context) -> Result<H::Resp> {
+        (**self).process_with_context(msg, ctx)
+    }
+
+    fn serialize(&self, msg: H::Resp) -> Result<Bytes> {
+        (**self).serialize(msg)
+    }
+
+    fn deserialize(&self, buf: Bytes) -> Result<H::Req> {
+        (**self).deserialize(buf)
+    }
+}
+
+impl<H: MessageHandler + Send + ?Sized> MessageHandler for Arc<H> {
+    type Req = H::Req;
+    type Resp = H::Resp;
+
+    fn process(&self, msg: H::Req) -> Result<H::Resp> {
+        (**self).process(msg)
+    }
+
+    fn process_with_context(&self, msg: H::Req, ctx: &Context) -> Result<H::Resp> {
+        (**self).process_with_context(msg, ctx)
+    }
+
+    fn serialize(&self, msg: H::Resp) -> Result<Bytes> {
+        (**self).serialize(msg)
+    }
+
+    fn deserialize(&self, buf: Bytes) -> Result<H::Req> {
+        (**self).deserialize(buf)
+    }
+}
+
+impl<H: StreamingMessageHandler + ?Sized> StreamingMessageHandler for &H {
+    type Req = H::Req;
+    type Resp = H::Resp;
+
+    fn process(&self, msg: H::Req, ctx: &Context, responses: &mut ResponseSender<H::Resp>) -> Result<()> {
+        (**self).process(msg, ctx, responses)
+    }
+
+    fn serialize(&self, msg: H::Resp) -> Result<Bytes> {
+        (**self).serialize(msg)
+    }
+
+    fn deserialize(&self, buf: Bytes) -> Result<H::Req> {
+        (**self).deserialize(buf)
+    }
+}
+
+impl<H: StreamingMessageHandler + Send + ?Sized> StreamingMessageHandler for Arc<H> {
+    type Req = H::Req;
+    type Resp = H::Resp;
+
+    fn process(&self, msg: H::Req, ctx: &Context, responses: &mut ResponseSender<H::Resp>) -> Result<()> {
+        (**self).process(msg, ctx, responses)
+    }
+
+    fn serialize(&self, msg: H::Resp) -> Result<Bytes> {
+        (**self).serialize(msg)
+    }
+
+    fn deserialize(&self, buf: Bytes) -> Result<H::Req> {
+        (**self).deserialize(buf)
+    }
+}
+
+// A handler owned by a single worker thread, so it can keep mutable state such as caches or
+// scratch buffers without any locking of its own. One is built per worker thread, or per I/O
+// thread when running to completion.
+pub trait StatefulMessageHandler: Send {
+    type Req;
+    type Resp;
+    fn process(&mut self, msg: Self::Req, ctx: &Context) -> Result<Self::Resp>;
+    fn serialize(&mut self, msg: Self::Resp) -> Result<Bytes>;
+    fn deserialize(&mut self, buf: Bytes) -> Result<Self::Req>;
+}
+
+pub struct Unary<H> {
+    handler: H,
+}
+
+impl<H> Unary<H> {
+    pub fn new(handler: H) -> Unary<H> {
+        Unary {
+            handler,
+        }
+    }
+}
+
+impl<H: MessageHandler + Send> Dispatch for Unary<H> {
+    fn dispatch(&self, buf: Bytes, ctx: &Context, responses: &mut Responses) -> Result<()> {
+        let req = timed(ctx, Stage::Deserialize, || self.handler.deserialize(buf))
+            .chain_err(|| "unable to deserialize message")?;
+        let resp = timed(ctx, Stage::Process, || self.handler.process_with_context(req, ctx))
+            .chain_err(|| "unable to process message")?;
+        let buf = timed(ctx, Stage::Serialize, || self.handler.serialize(resp))
+            .chain_err(|| "unable to serialize response")?;
+        responses.send_last(buf)
+    }
+}
+
+pub struct Streaming<H> {
+    handler: H,
+}
+
+impl<H> Streaming<H> {
+    pub fn new(handler: H) -> Streaming<H> {
+        Streaming {
+            handler,
+        }
+    }
+}
+
+impl<H: StreamingMessageHandler + Send> Dispatch for Streaming<H> {
+    fn dispatch(&self, buf: Bytes, ctx: &Context, responses: &mut Responses) -> Result<()> {
+        let req = timed(ctx, Stage::Deserialize, || self.handler.deserialize(buf))
+            .chain_err(|| "unable to deserialize message")?;
+        let serialize = |msg: H::Resp| timed(ctx, Stage::Serialize, || self.handler.serialize(msg))
+            .chain_err(|| "unable to serialize response");
+        let mut sender = ResponseSender::new(&serialize, responses);
+        // includes the time spent serializing and queueing each response
+        timed(ctx, Stage::Process, || self.handler.process(req, ctx, &mut sender))
+            .chain_err(|| "unable to process message")
+    }
+}
+
+// Only ever used by the thread it was built for, so the lock is never contended. It is there
+// to make the handler Sync.
+pub struct PerWorker<H> {
+    handler: Mutex<H>,
+}
+
+impl<H> PerWorker<H> {
+    pub fn new(handler: H) -> PerWorker<H> {
+        PerWorker {
+            handler: Mutex::new(handler),
+        }
+    }
+}
+
+impl<H: StatefulMessageHandler> Dispatch for PerWorker<H> {
+    fn dispatch(&self, buf: Bytes, ctx: &Context, responses: &mut Responses) -> Result<()> {
+        let mut handler = match self.handler.lock() {
+            Ok(handler) => handler,
+            Err(_) => return Err("handler panicked on an earlier request".into()),
+        };
+        let req = timed(ctx, Stage::Deserialize, || handler.deserialize(buf))
+            .chain_err(|| "unable to deserialize message")?;
+        let resp = timed(ctx, Stage::Process, || handler.process(req, ctx))
+            .chain_err(|| "unable to process message")?;
+        let buf = timed(ctx, Stage::Serialize, || handler.serialize(resp))
+            .chain_err(|| "unable to serialize response")?;
+        responses.send_last(buf)
+    }
+}
@@ -0,0 +1,387 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+
+use ::MessageHandler;
+use metrics::{Histogram, HistogramSnapshot};
+use worker::Context;
+use errors::*;
+
+// Code that runs around a handler's process step. It may look at or change the request,
+// reject it, or pass it to `next` and work with whatever comes back.
+pub trait Middleware<Req, Resp>: Sync {
+    fn call(&self, msg: Req, ctx: &Context, next: &dyn Fn(Req) -> Result<Resp>) -> Result<Resp>;
+}
+
+// A handler wrapped in a middleware. Layered is a MessageHandler itself, so layers stack by
+// wrapping again, and the outermost one runs first.
+pub struct Layered<H, M> {
+    handler: H,
+    middleware: M,
+}
+
+impl<H, M> Layered<H, M> {
+    pub const fn new(handler: H, middleware: M) -> Layered<H, M> {
+        Layered {
+            handler,
+            middleware,
+        }
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn middleware(&self) -> &M {
+        &self.middleware
+    }
+}
+
+impl<H, M> MessageHandler for Layered<H, M>
+        where H: MessageHandler, M: Middleware<H::Req, H::Resp> {
+    type Req = H::Req;
+    type Resp = H::Resp;
+
+    // Middleware needs the request's context, so without one there is nothing to run the
+    // handler through.
+    fn process(&self, _msg: H::Req) -> Result<H::Resp> {
+        Err("layered handlers need a context".into())
+    }
+
+    fn process_with_context(&self, msg: H::Req, ctx: &Context) -> Result<H::Resp> {
+        self.middleware.call(msg, ctx, &|msg| self.handler.process_with_context(msg, ctx))
+    }
+
+    fn serialize(&self, msg: H::Resp) -> Result<Bytes> {
+        self.handler.serialize(msg)
+    }
+
+    fn deserialize(&self, buf: Bytes) -> Result<H::Req> {
+        self.handler.deserialize(buf)
+    }
+}
+
+// Turns a handler into one that takes and returns frames, decoding and encoding inside its
+// process step, so middleware layered on top sees the raw bytes. The stage timings then count
+// decoding and encoding as processing.
+pub struct Raw<H> {
+    handler: H,
+}
+
+impl<H> Raw<H> {
+    pub const fn new(handler: H) -> Raw<H> {
+        Raw {
+            handler,
+        }
+    }
+}
+
+impl<H: MessageHandler> MessageHandler for Raw<H> {
+    type Req = Bytes;
+    type Resp = Bytes;
+
+    fn process(&self, buf: Bytes) -> Result<Bytes> {
+        let msg = self.handler.deserialize(buf)?;
+        self.handler.serialize(self.handler.process(msg)?)
+    }
+
+    fn process_with_context(&self, buf: Bytes, ctx: &Context) -> Result<Bytes> {
+        let msg = self.handler.deserialize(buf)?;
+        self.handler.serialize(self.handler.process_with_context(msg, ctx)?)
+    }
+
+    fn serialize(&self, buf: Bytes) -> Result<Bytes> {
+        Ok(buf)
+    }
+
+    fn deserialize(&self, buf: Bytes) -> Result<Bytes> {
+        Ok(buf)
+    }
+}
+
+// Records how long the layers inside it take, whether they succeed or not.
+#[derive(Debug, Default)]
+pub struct Timing {
+    histogram: Histogram,
+}
+
+impl Timing {
+    pub fn new() -> Timing {
+        Timing {
+            histogram: Histogram::new(),
+        }
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        self.histogram.snapshot()
+    }
+}
+
+impl<Req, Resp> Middleware<Req, Resp> for Timing {
+    fn call(&self, msg: Req, _ctx: &Context, next: &dyn Fn(Req) -> Result<Resp>) -> Result<Resp> {
+        let start = Instant::now();
+        let result = next(msg);
+        self.histogram.record(start.elapsed());
+        result
+    }
+}
+
+// Logs every request under the given name, at info when it succeeds and warn when it fails.
+#[derive(Debug, Clone, Copy)]
+pub struct Logging {
+    name: &'static str,
+}
+
+impl Logging {
+    pub const fn new(name: &'static str) -> Logging {
+        Logging {
+            name,
+        }
+    }
+}
+
+impl<Req, Resp> Middleware<Req, Resp> for Logging {
+    fn call(&self, msg: Req, ctx: &Context, next: &dyn Fn(Req) -> Result<Resp>) -> Result<Resp> {
+        let start = Instant::now();
+        let result = next(msg);
+        match result {
+            Ok(_) => info!("{}: request from connection {} took {:?}", self.name, ctx.conn(), start.elapsed()),
+            Err(ref e) => warn!("{}: request from connection {} failed after {:?}: {}", self.name, ctx.conn(),
+                start.elapsed(), e),
+        }
+        result
+    }
+}
+
+// Fails requests with ErrorKind::Overloaded while `limit` others are already inside it, across
+// every thread sharing the layer.
+#[derive(Debug)]
+pub struct ConcurrencyLimit {
+    limit: usize,
+    in_flight: AtomicUsize,
+}
+
+impl ConcurrencyLimit {
+    pub const fn new(limit: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            limit,
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+impl<Req, Resp> Middleware<Req, Resp> for ConcurrencyLimit {
+    fn call(&self, msg: Req, _ctx: &Context, next: &dyn Fn(Req) -> Result<Resp>) -> Result<Resp> {
+        let taken = self.in_flight.fetch_add(1, Ordering::AcqRel);
+        let _slot = Slot(&self.in_flight);
+        if taken >= self.limit {
+            return Err(ErrorKind::Overloaded(self.limit).into());
+        }
+        next(msg)
+    }
+}
+
+// Gives a ConcurrencyLimit slot back when dropped, even if the handler panics.
+struct Slot<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for Slot<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// Moves the request's deadline up to `limit` from now for the layers inside it, where handlers
+// see it through Context::deadline and is_cancelled and can give up early. Handlers can't be
+// interrupted, so one that doesn't check runs to the end. Either way a request that overruns
+// fails with ErrorKind::TimedOut and whatever it returned is dropped.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout {
+    limit: Duration,
+}
+
+impl Timeout {
+    pub const fn new(limit: Duration) -> Timeout {
+        Timeout {
+            limit,
+        }
+    }
+}
+
+impl<Req, Resp> Middleware<Req, Resp> for Timeout {
+    fn call(&self, msg: Req, ctx: &Context, next: &dyn Fn(Req) -> Result<Resp>) -> Result<Resp> {
+        let deadline = Instant::now() + self.limit;
+        let result = ctx.with_deadline(deadline, || next(msg));
+        if Instant::now() >= deadline {
+            return Err(ErrorKind::TimedOut(self.limit).into());
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use bytes::Bytes;
+    use ::MessageHandler;
+    use connection::{ConnId, ConnInfo};
+    use metrics::Metrics;
+    use pubsub::Publisher;
+    use service::{ServiceHandler, Service, Utf8Codec};
+    use worker::{self, Context, Cancellation};
+    use errors::*;
+    use super::{Middleware, Layered, Raw, Timing, ConcurrencyLimit, Timeout};
+
+    struct Sleeper;
+
+    impl Service for Sleeper {
+        type Req = String;
+        type Resp = String;
+
+        fn process(&self, msg: String) -> Result<String> {
+            if msg == "panic" {
+                panic!("asked to panic");
+            }
+            let millis = msg.parse().chain_err(|| "not a number")?;
+            thread::sleep(Duration::from_millis(millis));
+            Ok(msg)
+        }
+    }
+
+    // Works until its request is cancelled, for at most a second.
+    struct Patient;
+
+    impl MessageHandler for Patient {
+        type Req = Bytes;
+        type Resp = Bytes;
+
+        fn process(&self, _buf: Bytes) -> Result<Bytes> {
+            Err("context required".into())
+        }
+
+        fn process_with_context(&self, buf: Bytes, ctx: &Context) -> Result<Bytes> {
+            let start = Instant::now();
+            while !ctx.is_cancelled() {
+                if start.elapsed() > Duration::from_secs(1) {
+                    return Ok(buf);
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err("gave up".into())
+        }
+
+        fn serialize(&self, buf: Bytes) -> Result<Bytes> {
+            Ok(buf)
+        }
+
+        fn deserialize(&self, buf: Bytes) -> Result<Bytes> {
+            Ok(buf)
+        }
+    }
+
+    // Rejects frames that start with a byte other than a digit before they are decoded.
+    struct Digits;
+
+    impl Middleware<Bytes, Bytes> for Digits {
+        fn call(&self, buf: Bytes, _ctx: &Context, next: &dyn Fn(Bytes) -> Result<Bytes>) -> Result<Bytes> {
+            match buf.first() {
+                Some(b) if b.is_ascii_digit() => next(buf),
+                _ => Err("frame must start with a digit".into()),
+            }
+        }
+    }
+
+    fn handle<H: MessageHandler>(handler: &H, msg: &'static [u8]) -> Result<Bytes> {
+        let (_, sink) = worker::write_pipeline();
+        let publisher = Publisher::new(sink, false);
+        let metrics = Metrics::new(1);
+        let cancellation = Cancellation::new(Arc::new(ConnInfo::new(None)), None);
+        let ctx = Context::new(ConnId::new(0, 0, 0), &cancellation, &publisher, &metrics);
+        let req = handler.deserialize(Bytes::from_static(msg))?;
+        handler.serialize(handler.process_with_context(req, &ctx)?)
+    }
+
+    #[test]
+    fn layers_stack() {
+        let inner = Layered::new(ServiceHandler::new(Utf8Codec, Sleeper), Timeout::new(Duration::from_millis(20)));
+        let handler = Layered::new(inner, Timing::new());
+
+        assert_eq!(handle(&handler, b"0").expect("couldn't handle"), Bytes::from_static(b"0"));
+        match handle(&handler, b"30") {
+            Err(Error(ErrorKind::TimedOut(_), _)) => {},
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert!(handle(&handler, b"x").is_err());
+        assert_eq!(handler.middleware().snapshot().count, 3);
+    }
+
+    #[test]
+    fn timeouts_set_a_deadline() {
+        let handler = Layered::new(Patient, Timeout::new(Duration::from_millis(20)));
+        let start = Instant::now();
+        match handle(&handler, b"x") {
+            Err(Error(ErrorKind::TimedOut(_), _)) => {},
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        // the handler gave up rather than running out its second
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn deadlines_only_move_up() {
+        let (_, sink) = worker::write_pipeline();
+        let publisher = Publisher::new(sink, false);
+        let metrics = Metrics::new(1);
+        let soon = Instant::now() + Duration::from_millis(10);
+        let later = soon + Duration::from_secs(1);
+        let cancellation = Cancellation::new(Arc::new(ConnInfo::new(None)), Some(soon));
+        let ctx = Context::new(ConnId::new(0, 0, 0), &cancellation, &publisher, &metrics);
+
+        assert_eq!(ctx.with_deadline(later, || ctx.deadline()), Some(soon));
+        assert_eq!(ctx.with_deadline(soon - Duration::from_millis(5), || ctx.cancellation().deadline()),
+            Some(soon - Duration::from_millis(5)));
+        assert_eq!(ctx.deadline(), Some(soon));
+    }
+
+    #[test]
+    fn layered_handlers_need_a_context() {
+        let handler = Layered::new(ServiceHandler::new(Utf8Codec, Sleeper), Timing::new());
+        assert!(handler.process("0".to_owned()).is_err());
+        assert_eq!(handler.middleware().snapshot().count, 0);
+    }
+
+    #[test]
+    fn raw_layers_see_frames() {
+        let handler = Layered::new(Raw::new(ServiceHandler::new(Utf8Codec, Sleeper)), Digits);
+        assert_eq!(handle(&handler, b"1").expect("couldn't handle"), Bytes::from_static(b"1"));
+        assert!(handle(&handler, b"abc").is_err());
+    }
+
+    #[test]
+    fn concurrency_limit() {
+        static LIMITED: Layered<ServiceHandler<Utf8Codec, Sleeper>, ConcurrencyLimit> =
+            Layered::new(ServiceHandler::new(Utf8Codec, Sleeper), ConcurrencyLimit::new(1));
+
+        let slow = thread::spawn(|| handle(&LIMITED, b"100"));
+        while LIMITED.middleware().in_flight() == 0 {
+            thread::yield_now();
+        }
+        match handle(&LIMITED, b"0") {
+            Err(Error(ErrorKind::Overloaded(1), _)) => {},
+            other => panic!("expected to be turned away, got {:?}", other),
+        }
+        assert!(slow.join().expect("thread panicked").is_ok());
+        assert_eq!(LIMITED.middleware().in_flight(), 0);
+        assert!(handle(&LIMITED, b"0").is_ok());
+
+        // a panicking handler gives its slot back
+        assert!(thread::spawn(|| handle(&LIMITED, b"panic")).join().is_err());
+        assert_eq!(LIMITED.middleware().in_flight(), 0);
+        assert!(handle(&LIMITED, b"0").is_ok());
+    }
+}
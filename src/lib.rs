@@ -7,13 +7,53 @@ extern crate log;
 extern crate byteorder;
 extern crate mio;
 extern crate slab;
+extern crate net2;
+extern crate iovec;
+extern crate bytes;
+extern crate crossbeam_queue;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(any(feature = "json", feature = "bincode", feature = "msgpack", feature = "cbor"))]
+extern crate serde;
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(feature = "bincode")]
+extern crate bincode;
+#[cfg(feature = "msgpack")]
+extern crate rmp_serde;
+#[cfg(feature = "cbor")]
+extern crate serde_cbor;
+#[cfg(feature = "prost")]
+extern crate prost;
+#[cfg(feature = "tokio")]
+extern crate tokio;
 
 mod worker;
 mod connection;
 mod server;
+mod pubsub;
+mod stream;
+mod handler;
+mod pool;
+mod metrics;
+mod admin;
+mod trace;
+mod access_log;
+mod service;
+mod router;
+mod layer;
+mod executor;
+mod async_handler;
+#[cfg(any(feature = "json", feature = "bincode", feature = "msgpack", feature = "cbor"))]
+mod codecs;
+#[cfg(feature = "prost")]
+mod proto;
+#[cfg(feature = "tokio")]
+mod tokio_server;
 
+#[allow(deprecated)]
 pub mod errors {
-    use worker::MsgBuf;
+    use worker::WorkerMsg;
 
     // Create the Error, ErrorKind, ResultExt, and Result types
     error_chain! {
@@ -22,83 +62,361 @@ pub mod errors {
             Io(::std::io::Error) #[cfg(unix)];
             Net(::std::net::AddrParseError);
             TryChanRecv(::mpsc::TryRecvError);
-            TryChanSend(::mpsc::TrySendError<MsgBuf>);
+            TryChanSend(::mpsc::TrySendError<WorkerMsg>);
             ChanRecv(::mpsc::RecvError);
-            ChanSend(::mpsc::SendError<MsgBuf>);
+            ChanSend(::mpsc::SendError<WorkerMsg>);
+        }
+
+        errors {
+            PipelineClosed {
+                description("write pipeline closed")
+                display("write pipeline closed, the I/O thread has exited")
+            }
+
+            Codec(codec: &'static str, reason: String) {
+                description("unable to encode or decode message")
+                display("{} codec error: {}", codec, reason)
+            }
+
+            Overloaded(limit: usize) {
+                description("too many requests in progress")
+                display("already handling {} requests", limit)
+            }
 
+            TimedOut(limit: ::std::time::Duration) {
+                description("request timed out")
+                display("request took longer than {:?}", limit)
+            }
         }
     }
 }
 
-use std::net::SocketAddr;
-use mio::Poll;
-use mio::net::TcpListener;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use mio::{Poll, Registration, SetReadiness, Ready};
 use errors::*;
-use worker::{Worker,MsgBuf};
+use worker::{Worker,WorkerMsg,MessageSink,Outbound,Processor};
 use server::Server;
 use std::sync::mpsc;
 use std::sync::mpsc::Sender;
 use std::thread;
+use std::sync::Arc;
+use handler::{Dispatch, Unary, Streaming, PerWorker};
+use async_handler::Async;
+use metrics::Metrics;
+use admin::{Admin, Health, Alive, Connections};
+use access_log::RequestLog;
+
+pub use pubsub::Publisher;
+pub use worker::{Context, Cancellation};
+pub use connection::{ConnId, ConnectionSnapshot};
+pub use stream::{StreamingMessageHandler, ResponseSender};
+pub use bytes::Bytes;
+pub use metrics::{Stats, HistogramSnapshot};
+pub use access_log::{LogConfig, LogFormat, LogTarget};
+pub use service::{Codec, Service, ServiceHandler, RawCodec, Utf8Codec};
+pub use router::Router;
+pub use handler::StatefulMessageHandler;
+pub use async_handler::{AsyncMessageHandler, AsyncContext, ResponseFuture};
+pub use layer::{Middleware, Layered, Raw, Timing, Logging, ConcurrencyLimit, Timeout};
+#[cfg(feature = "json")]
+pub use codecs::JsonCodec;
+#[cfg(feature = "bincode")]
+pub use codecs::BincodeCodec;
+#[cfg(feature = "msgpack")]
+pub use codecs::MsgpackCodec;
+#[cfg(feature = "cbor")]
+pub use codecs::CborCodec;
+#[cfg(feature = "prost")]
+pub use proto::{ProstCodec, Envelope};
+#[cfg(feature = "tokio")]
+pub use tokio_server::TokioServer;
 
 
 pub trait MessageHandler: Sync {
     type Req;
     type Resp;
     fn process(&self, msg: Self::Req) -> Result<Self::Resp>;
-    fn serialize(&self, msg: Self::Resp) -> Result<Vec<u8>>;
-    fn deserialize(&self, buf: Vec<u8>) -> Result<Self::Req>;
+    fn process_with_context(&self, msg: Self::Req, _ctx: &Context) -> Result<Self::Resp> {
+        self.process(msg)
+    }
+    fn serialize(&self, msg: Self::Resp) -> Result<Bytes>;
+    fn deserialize(&self, buf: Bytes) -> Result<Self::Req>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    WorkerPool,
+    RunToCompletion,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub mode: Mode,
+    pub num_workers: u16,
+    pub io_threads: usize,
+    pub correlation: bool,
+    pub read_budget: usize,
+    pub admin_addr: Option<SocketAddr>,
+    pub access_log: Option<LogConfig>,
+    // requests taking at least slow_threshold, from being read to their last response being
+    // queued, go to the slow log along with their per stage timings
+    pub slow_log: Option<LogConfig>,
+    pub slow_threshold: Duration,
+    // requests still waiting for a worker this long after being read are dropped, and
+    // Context::is_cancelled turns true for handlers still working on them
+    pub request_timeout: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            mode: Mode::WorkerPool,
+            num_workers: 4,
+            io_threads: 1,
+            correlation: false,
+            read_budget: 64,
+            admin_addr: None,
+            access_log: None,
+            slow_log: None,
+            slow_threshold: Duration::from_millis(100),
+            request_timeout: None,
+        }
+    }
 }
 
 pub struct Shutdown {
-    read_tx: Vec<Sender<MsgBuf>>,
-    write_tx: Sender<MsgBuf>,
+    read_tx: Vec<Sender<WorkerMsg>>,
+    sink: MessageSink,
+    publisher: Publisher,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    conns: Connections,
+    admin: Option<(SocketAddr, SetReadiness)>,
 }
 
 impl Shutdown {
     pub fn shutdown(&self) -> Result<()> {
+        self.drain();
         for tx in &self.read_tx {
-            tx.send(MsgBuf::shutdown_msg())?;
+            tx.send(WorkerMsg::Shutdown)?;
+        }
+        self.sink.send(Outbound::Shutdown)?;
+
+        if let Some((_, ref stop)) = self.admin {
+            stop.set_readiness(Ready::readable())?;
         }
-        self.write_tx.send(MsgBuf::shutdown_msg())?;
 
         Ok(())
     }
+
+    // Fails /readyz on the admin port so load balancers stop sending new connections, while
+    // the server keeps serving the ones it has.
+    pub fn drain(&self) {
+        self.health.drain();
+    }
+
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin.as_ref().map(|&(addr, _)| addr)
+    }
+
+    pub fn publisher(&self) -> Publisher {
+        self.publisher.clone()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.metrics.stats()
+    }
+
+    pub fn connections(&self) -> Result<Vec<ConnectionSnapshot>> {
+        self.conns.list()
+    }
+
+    // Returns false if the connection had already closed.
+    pub fn close_connection(&self, id: ConnId) -> Result<bool> {
+        self.conns.close(id)
+    }
+
+    // Closes every connection from the given address and returns how many there were.
+    pub fn close_peer(&self, ip: IpAddr) -> Result<usize> {
+        self.conns.close_peer(ip)
+    }
 }
 
 pub fn bootstrap<I, O>(listen_addr: SocketAddr, num_workers: u16, 
-    handler: &'static MessageHandler<Req=I, Resp=O>) -> Result<(Shutdown)> {
-    assert!(num_workers >= 2, "num_wokers must be at least two");
+    handler: &'static dyn MessageHandler<Req=I, Resp=O>) -> Result<Shutdown> {
+    let config = Config {
+        num_workers,
+        ..Config::default()
+    };
+    bootstrap_with_config(listen_addr, config, handler)
+}
 
-    let sock = TcpListener::bind(&listen_addr)?;
-    let (write_tx, write_rx) = mpsc::channel();
-    let mut all_read_tx : Vec<Sender<MsgBuf>> = vec!{};
+pub fn bootstrap_with_config<I, O>(listen_addr: SocketAddr, config: Config,
+    handler: &'static dyn MessageHandler<Req=I, Resp=O>) -> Result<Shutdown> {
+    bootstrap_shared(listen_addr, config, handler)
+}
 
-    let sd = Shutdown {
-        read_tx: all_read_tx.to_owned(),
-        write_tx: write_tx.clone(),
+// Serves a handler that isn't a static, such as one built at startup from its configuration.
+// A &'static handler works here too.
+pub fn bootstrap_shared<H>(listen_addr: SocketAddr, config: Config, handler: H) -> Result<Shutdown>
+        where H: MessageHandler + Send + 'static {
+    let handler: Arc<dyn Dispatch> = Arc::new(Unary::new(handler));
+    start(listen_addr, config, &|_| handler.clone())
+}
+
+pub fn bootstrap_streaming<I, O>(listen_addr: SocketAddr, config: Config,
+    handler: &'static dyn StreamingMessageHandler<Req=I, Resp=O>) -> Result<Shutdown> {
+    bootstrap_streaming_shared(listen_addr, config, handler)
+}
+
+pub fn bootstrap_streaming_shared<H>(listen_addr: SocketAddr, config: Config, handler: H) -> Result<Shutdown>
+        where H: StreamingMessageHandler + Send + 'static {
+    let handler: Arc<dyn Dispatch> = Arc::new(Streaming::new(handler));
+    start(listen_addr, config, &|_| handler.clone())
+}
+
+// Calls `factory` once for every thread that runs requests, passing the worker index, or the
+// I/O thread index when running to completion. Each thread gets its own handler and is the
+// only one to use it.
+pub fn bootstrap_per_worker<F, H>(listen_addr: SocketAddr, config: Config, factory: F) -> Result<Shutdown>
+        where F: Fn(usize) -> H, H: StatefulMessageHandler + 'static {
+    start(listen_addr, config, &|idx| Arc::new(PerWorker::new(factory(idx))))
+}
+
+// Each worker keeps polling the futures of requests it has started while it reads new ones,
+// so a few workers can have many requests waiting on other services.
+pub fn bootstrap_async<H>(listen_addr: SocketAddr, config: Config, handler: H) -> Result<Shutdown>
+        where H: AsyncMessageHandler + Send + 'static, H::Resp: 'static {
+    let handler: Arc<dyn Dispatch> = Arc::new(Async::new(handler));
+    start(listen_addr, config, &|_| handler.clone())
+}
+
+pub fn bootstrap_router(listen_addr: SocketAddr, config: Config, router: Router) -> Result<Shutdown> {
+    let router: Arc<dyn Dispatch> = Arc::new(router);
+    start(listen_addr, config, &|_| router.clone())
+}
+
+// The tokio backend serves the same handlers with the same framing, as tasks on the runtime
+// it is started from, which needs its I/O and time drivers. Config::mode, num_workers,
+// io_threads and admin_addr don't apply to it.
+#[cfg(feature = "tokio")]
+pub fn bootstrap_tokio<H>(listener: tokio::net::TcpListener, config: Config, handler: H) -> Result<TokioServer>
+        where H: MessageHandler + Send + 'static {
+    tokio_server::serve(listener, &config, Arc::new(Unary::new(handler)))
+}
+
+#[cfg(feature = "tokio")]
+pub fn bootstrap_tokio_streaming<H>(listener: tokio::net::TcpListener, config: Config, handler: H)
+        -> Result<TokioServer> where H: StreamingMessageHandler + Send + 'static {
+    tokio_server::serve(listener, &config, Arc::new(Streaming::new(handler)))
+}
+
+#[cfg(feature = "tokio")]
+pub fn bootstrap_tokio_async<H>(listener: tokio::net::TcpListener, config: Config, handler: H)
+        -> Result<TokioServer> where H: AsyncMessageHandler + Send + 'static, H::Resp: 'static {
+    tokio_server::serve(listener, &config, Arc::new(Async::new(handler)))
+}
+
+#[cfg(feature = "tokio")]
+pub fn bootstrap_tokio_router(listener: tokio::net::TcpListener, config: Config, router: Router)
+        -> Result<TokioServer> {
+    tokio_server::serve(listener, &config, Arc::new(router))
+}
+
+fn start(listen_addr: SocketAddr, config: Config, handlers: &dyn Fn(usize) -> Arc<dyn Dispatch>) -> Result<Shutdown> {
+    // num_workers counts the I/O thread, which is the only one needed to run to completion
+    let num_workers = match config.mode {
+        Mode::WorkerPool => {
+            assert!(config.num_workers >= 2, "num_wokers must be at least two");
+            config.num_workers
+        },
+        Mode::RunToCompletion => 1,
     };
-    
-    let (source, sink) = worker::write_pipeline(write_tx, write_rx);
+    assert!(config.io_threads >= 1, "io_threads must be at least one");
+    assert!(config.read_budget >= 1, "read_budget must be at least one");
+
+    let socks = server::bind_listeners(&listen_addr, config.io_threads)?;
+    let mut all_read_tx : Vec<Sender<WorkerMsg>> = vec!{};
+    let mut sources = vec!{};
+    let mut sinks = vec!{};
 
-    for _ in 0..num_workers-1 {
+    for _ in 0..config.io_threads {
+        let (source, sink) = worker::write_pipeline();
+        sources.push(source);
+        sinks.push(sink);
+    }
+    let sink = MessageSink::join(sinks);
+
+    let publisher = Publisher::new(sink.clone(), config.correlation);
+    let metrics = Arc::new(Metrics::new(num_workers as usize - 1));
+    let health = Arc::new(Health::new(num_workers as usize - 1, config.io_threads));
+    let conns = Connections::new(sink.clone());
+    let log = RequestLog::start(&config)?;
+    let processor = |idx| Processor::new(handlers(idx), publisher.clone(), metrics.clone(), config.correlation,
+        log.clone(), config.request_timeout);
+
+    for idx in 0..num_workers as usize - 1 {
         let (read_tx, read_rx) = mpsc::channel();
+        let wake_tx = read_tx.clone();
         all_read_tx.push(read_tx);
         let worker_sink = sink.clone();
+        let worker_processor = processor(idx);
+        let worker_health = health.clone();
 
         thread::spawn(move || {
-            let mut worker = Worker::new(handler, read_rx, worker_sink);
+            let _alive = Alive::worker(worker_health);
+            let mut worker = Worker::new(idx, worker_processor, read_rx, wake_tx, worker_sink);
             info!("worker starting");
             worker.run().expect("failed to start worker");
         });
     }
 
-    thread::spawn(move || {
-        let mut poll = Poll::new().expect("Failed to create poll");
-        let mut server = Server::new(sock, all_read_tx, source);
+    let admin = match config.admin_addr {
+        Some(addr) => {
+            let sock = admin::bind(&addr)?;
+            let admin_addr = sock.local_addr()?;
+            let (stop, stop_readiness) = Registration::new2();
+            let mut admin = Admin::new(sock, stop, metrics.clone(), health.clone(), conns.clone());
 
-        info!("server starting on {}", listen_addr);
-        server.run(&mut poll).expect("failed to start server");
-    });
+            thread::spawn(move || {
+                info!("admin listener starting on {}", admin_addr);
+                admin.run().expect("failed to run admin listener");
+            });
+            Some((admin_addr, stop_readiness))
+        },
+        None => None,
+    };
+
+    let sd = Shutdown {
+        read_tx: all_read_tx.to_owned(),
+        publisher: publisher.clone(),
+        sink,
+        metrics: metrics.clone(),
+        health: health.clone(),
+        conns,
+        admin,
+    };
+
+    for (io, (sock, source)) in socks.into_iter().zip(sources).enumerate() {
+        let read_tx = all_read_tx.clone();
+        let read_budget = config.read_budget;
+        let server_metrics = metrics.clone();
+        let server_health = health.clone();
+        let inline = match config.mode {
+            Mode::WorkerPool => None,
+            Mode::RunToCompletion => Some(processor(io)),
+        };
+
+        thread::spawn(move || {
+            let _alive = Alive::io(server_health);
+            let mut poll = Poll::new().expect("Failed to create poll");
+            let mut server = Server::new(io, sock, read_tx, source, inline, read_budget, server_metrics);
+
+            info!("server {} starting on {}", io, listen_addr);
+            server.run(&mut poll).expect("failed to start server");
+        });
+    }
     
     Ok(sd)
 }
@@ -109,9 +427,64 @@ pub fn bootstrap<I, O>(listen_addr: SocketAddr, num_workers: u16,
 #[cfg(test)]
 mod tests {
 
-    use std::net::SocketAddr;
-    use ::MessageHandler;
+    use std::net::{SocketAddr, TcpStream};
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use std::fs;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{self, Poll};
+    use byteorder::{ByteOrder, BigEndian};
+    use mio::Token;
+    use mio::net::TcpStream as MioTcpStream;
+    use ::{MessageHandler, StreamingMessageHandler, ResponseSender, Context, Config, Mode, Bytes, Publisher,
+        LogConfig, LogFormat, LogTarget, Router, StatefulMessageHandler, AsyncMessageHandler, AsyncContext,
+        ResponseFuture};
+    use connection::{Connection, ConnId, ConnInfo};
+    use handler::Unary;
+    use metrics::Metrics;
+    use pool::BufferPool;
+    use trace::ConnSpan;
+    use worker::{self, MsgBuf, Processor, Progress, Request};
     use ::errors::*;
+
+    // Counts allocations made by the current thread while counting is switched on, so tests
+    // running in parallel don't show up in each other's numbers.
+    struct CountingAlloc;
+
+    thread_local!(static ALLOCATIONS: Cell<Option<u64>> = const { Cell::new(None) });
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| {
+                if let Some(n) = count.get() {
+                    count.set(Some(n + 1));
+                }
+            });
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOC: CountingAlloc = CountingAlloc;
+
+    fn count_allocations(on: bool) -> u64 {
+        ALLOCATIONS.with(|count| {
+            let n = count.get().unwrap_or(0);
+            count.set(if on { Some(0) } else { None });
+            n
+        })
+    }
     
     struct Reverser{}
 
@@ -124,12 +497,12 @@ mod tests {
             Ok(msg)
         }
 
-        fn serialize(&self, msg: String) -> Result<Vec<u8>> {
-            Ok(msg.as_bytes().to_vec())
+        fn serialize(&self, msg: String) -> Result<Bytes> {
+            Ok(msg.into())
         }
 
-        fn deserialize(&self, buf: Vec<u8>) -> Result<String> {
-            match String::from_utf8(buf) {
+        fn deserialize(&self, buf: Bytes) -> Result<String> {
+            match String::from_utf8(buf.to_vec()) {
                 Ok(msg) => Ok(msg),
                 Err(_) => Err("couldn't build string".into())
             }
@@ -138,6 +511,139 @@ mod tests {
 
     static HANDLER: Reverser = Reverser{};
 
+    struct Notifier{}
+
+    impl MessageHandler for Notifier {
+        type Req = String;
+        type Resp = String;
+
+        fn process(&self, _msg: String) -> Result<String> {
+            Err("context required".into())
+        }
+
+        fn process_with_context(&self, msg: String, ctx: &Context) -> Result<String> {
+            let mut parts = msg.splitn(3, ' ');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("sub"), Some(topic), None) => ctx.publisher().subscribe(ctx.conn(), topic)?,
+                (Some("pub"), Some(topic), Some(body)) => ctx.publisher().publish(topic, body.to_owned())?,
+                (Some("all"), Some(body), None) => ctx.publisher().broadcast(body.to_owned())?,
+                _ => return Err("unknown command".into()),
+            }
+            Ok("ok".to_owned())
+        }
+
+        fn serialize(&self, msg: String) -> Result<Bytes> {
+            Ok(msg.into())
+        }
+
+        fn deserialize(&self, buf: Bytes) -> Result<String> {
+            match String::from_utf8(buf.to_vec()) {
+                Ok(msg) => Ok(msg),
+                Err(_) => Err("couldn't build string".into())
+            }
+        }
+    }
+
+    static NOTIFIER: Notifier = Notifier{};
+
+    struct Counter{}
+
+    impl StreamingMessageHandler for Counter {
+        type Req = u64;
+        type Resp = u64;
+
+        fn process(&self, msg: u64, _ctx: &Context, responses: &mut ResponseSender<u64>) -> Result<()> {
+            for i in 0..msg {
+                responses.send(i)?;
+            }
+            Ok(())
+        }
+
+        fn serialize(&self, msg: u64) -> Result<Bytes> {
+            let mut buf = vec![0u8; 8];
+            BigEndian::write_u64(&mut buf, msg);
+            Ok(buf.into())
+        }
+
+        fn deserialize(&self, buf: Bytes) -> Result<u64> {
+            if buf.len() != 8 {
+                return Err("expected eight bytes".into());
+            }
+            Ok(BigEndian::read_u64(&buf))
+        }
+    }
+
+    static COUNTER: Counter = Counter{};
+
+    // Streams "first", then holds the request until the client says it has it before sending
+    // "second". A server that sits on streamed responses gets "timed out" instead.
+    struct Relay {
+        received: AtomicBool,
+    }
+
+    impl StreamingMessageHandler for Relay {
+        type Req = Bytes;
+        type Resp = &'static str;
+
+        fn process(&self, _msg: Bytes, _ctx: &Context, responses: &mut ResponseSender<&'static str>) -> Result<()> {
+            responses.send("first")?;
+            for _ in 0..250 {
+                if self.received.load(Ordering::Acquire) {
+                    return responses.send("second");
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+            responses.send("timed out")
+        }
+
+        fn serialize(&self, msg: &'static str) -> Result<Bytes> {
+            Ok(Bytes::from_static(msg.as_bytes()))
+        }
+
+        fn deserialize(&self, buf: Bytes) -> Result<Bytes> {
+            Ok(buf)
+        }
+    }
+
+    static RELAY: Relay = Relay { received: AtomicBool::new(false) };
+    #[cfg(feature = "tokio")]
+    static TOKIO_RELAY: Relay = Relay { received: AtomicBool::new(false) };
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        for _ in 0..50 {
+            if let Ok(sock) = TcpStream::connect(addr) {
+                sock.set_read_timeout(Some(Duration::from_secs(5))).expect("couldn't set timeout");
+                return sock;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("couldn't connect to {}", addr);
+    }
+
+    fn send_frame(sock: &mut TcpStream, msg: &[u8]) {
+        let mut len_buf = [0u8; 8];
+        BigEndian::write_u64(&mut len_buf, msg.len() as u64);
+        sock.write_all(&len_buf).expect("couldn't write length");
+        sock.write_all(msg).expect("couldn't write message");
+    }
+
+    fn read_frame(sock: &mut TcpStream) -> Vec<u8> {
+        let mut len_buf = [0u8; 8];
+        sock.read_exact(&mut len_buf).expect("couldn't read length");
+        let mut buf = vec![0u8; BigEndian::read_u64(&len_buf) as usize];
+        sock.read_exact(&mut buf).expect("couldn't read message");
+        buf
+    }
+
+    fn read_string(sock: &mut TcpStream) -> String {
+        String::from_utf8(read_frame(sock)).expect("couldn't build string")
+    }
+
+    fn request(sock: &mut TcpStream, msg: &str) -> String {
+        send_frame(sock, msg.as_bytes());
+        read_string(sock)
+    }
+
     #[test]
     fn boot() {
         let addr: SocketAddr = "127.0.0.1:7866".parse().expect("couldn't parse address string");
@@ -151,5 +657,1013 @@ mod tests {
             }
         }
     }
-}
 
+    #[test]
+    fn shutdown_closes_listener() {
+        let addr: SocketAddr = "127.0.0.1:7869".parse().expect("couldn't parse address string");
+        let sd = ::bootstrap(addr, 2, &HANDLER).expect("couldn't start server");
+        let mut sock = connect(addr);
+        assert_eq!(request(&mut sock, "abc"), "cba");
+
+        sd.shutdown().expect("couldn't shut down");
+        for _ in 0..100 {
+            if TcpStream::connect(addr).is_err() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("server still accepting connections after shutdown");
+    }
+
+    #[test]
+    fn publish_to_subscribers() {
+        let addr: SocketAddr = "127.0.0.1:7867".parse().expect("couldn't parse address string");
+        let _sd = ::bootstrap(addr, 3, &NOTIFIER).expect("couldn't start server");
+
+        let mut first = connect(addr);
+        let mut second = connect(addr);
+        let mut publisher = connect(addr);
+
+        assert_eq!(request(&mut first, "sub news"), "ok");
+        assert_eq!(request(&mut second, "sub news"), "ok");
+        assert_eq!(request(&mut publisher, "pub news hello"), "ok");
+
+        assert_eq!(read_string(&mut first), "hello");
+        assert_eq!(read_string(&mut second), "hello");
+
+        // the broadcast is queued ahead of the reply, so the publisher sees its own message first
+        assert_eq!(request(&mut publisher, "all everyone"), "everyone");
+        assert_eq!(read_string(&mut publisher), "ok");
+        assert_eq!(read_string(&mut first), "everyone");
+        assert_eq!(read_string(&mut second), "everyone");
+    }
+
+    fn read_response(sock: &mut TcpStream) -> (u64, u8, Vec<u8>) {
+        let mut frame = read_frame(sock);
+        let body = frame.split_off(9);
+        (BigEndian::read_u64(&frame[..8]), frame[8], body)
+    }
+
+    fn correlated(id: u64, msg: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; 8];
+        BigEndian::write_u64(&mut frame, id);
+        frame.extend_from_slice(msg);
+        frame
+    }
+
+    #[test]
+    fn correlated_pushes() {
+        let addr: SocketAddr = "127.0.0.1:7885".parse().expect("couldn't parse address string");
+        let config = Config {
+            num_workers: 2,
+            correlation: true,
+            ..Config::default()
+        };
+        let sd = ::bootstrap_with_config(addr, config, &NOTIFIER).expect("couldn't start server");
+
+        let mut socks: Vec<TcpStream> = (0..2).map(|_| connect(addr)).collect();
+        for sock in &mut socks {
+            send_frame(sock, &correlated(1, b"sub news"));
+            assert_eq!(read_response(sock), (1, 1, b"ok".to_vec()));
+        }
+
+        // pushed messages carry id 0 and flag 2, so they can't be mistaken for replies
+        send_frame(&mut socks[0], &correlated(2, b"pub news hi"));
+        assert_eq!(read_response(&mut socks[0]), (0, 2, b"hi".to_vec()));
+        assert_eq!(read_response(&mut socks[0]), (2, 1, b"ok".to_vec()));
+        assert_eq!(read_response(&mut socks[1]), (0, 2, b"hi".to_vec()));
+
+        sd.publisher().broadcast("all").expect("couldn't broadcast");
+        for sock in &mut socks {
+            assert_eq!(read_response(sock), (0, 2, b"all".to_vec()));
+        }
+        sd.shutdown().expect("couldn't shut down");
+    }
+
+    #[test]
+    fn streamed_responses_go_out_as_sent() {
+        let addr: SocketAddr = "127.0.0.1:7886".parse().expect("couldn't parse address string");
+        let config = Config {
+            mode: Mode::RunToCompletion,
+            ..Config::default()
+        };
+        let sd = ::bootstrap_streaming(addr, config, &RELAY).expect("couldn't start server");
+        let mut sock = connect(addr);
+        send_frame(&mut sock, b"go");
+        assert_eq!(read_string(&mut sock), "first");
+        RELAY.received.store(true, Ordering::Release);
+        assert_eq!(read_string(&mut sock), "second");
+        sd.shutdown().expect("couldn't shut down");
+    }
+
+    #[test]
+    fn streaming_responses() {
+        let addr: SocketAddr = "127.0.0.1:7868".parse().expect("couldn't parse address string");
+        let config = Config {
+            num_workers: 2,
+            correlation: true,
+            ..Config::default()
+        };
+        let _sd = ::bootstrap_streaming(addr, config, &COUNTER).expect("couldn't start server");
+
+        let mut sock = connect(addr);
+        let mut req = vec![0u8; 16];
+        BigEndian::write_u64(&mut req[..8], 7);
+        BigEndian::write_u64(&mut req[8..], 3);
+        send_frame(&mut sock, &req);
+
+        for i in 0..3 {
+            let (id, flag, body) = read_response(&mut sock);
+            assert_eq!((id, flag), (7, 0));
+            assert_eq!(BigEndian::read_u64(&body), i);
+        }
+        assert_eq!(read_response(&mut sock), (7, 1, vec!{}));
+
+        BigEndian::write_u64(&mut req[..8], 8);
+        BigEndian::write_u64(&mut req[8..], 0);
+        send_frame(&mut sock, &req);
+        assert_eq!(read_response(&mut sock), (8, 1, vec!{}));
+    }
+
+    #[test]
+    fn multiple_io_threads() {
+        let addr: SocketAddr = "127.0.0.1:7870".parse().expect("couldn't parse address string");
+        let config = Config {
+            num_workers: 3,
+            io_threads: 3,
+            ..Config::default()
+        };
+        let _sd = ::bootstrap_with_config(addr, config, &NOTIFIER).expect("couldn't start server");
+
+        let mut socks: Vec<TcpStream> = (0..12).map(|_| connect(addr)).collect();
+        for sock in &mut socks {
+            assert_eq!(request(sock, "sub everyone"), "ok");
+        }
+
+        assert_eq!(request(&mut socks[0], "pub everyone hi"), "hi");
+        assert_eq!(read_string(&mut socks[0]), "ok");
+        for sock in &mut socks[1..] {
+            assert_eq!(read_string(sock), "hi");
+        }
+    }
+
+    #[test]
+    fn run_to_completion() {
+        let addr: SocketAddr = "127.0.0.1:7871".parse().expect("couldn't parse address string");
+        let config = Config {
+            mode: Mode::RunToCompletion,
+            io_threads: 2,
+            ..Config::default()
+        };
+        let sd = ::bootstrap_with_config(addr, config, &HANDLER).expect("couldn't start server");
+
+        let mut socks: Vec<TcpStream> = (0..4).map(|_| connect(addr)).collect();
+        for (i, sock) in socks.iter_mut().enumerate() {
+            assert_eq!(request(sock, &format!("abc{}", i)), format!("{}cba", i));
+        }
+        sd.shutdown().expect("couldn't shut down");
+    }
+
+    #[test]
+    fn pipelined_requests_beyond_read_budget() {
+        let addr: SocketAddr = "127.0.0.1:7872".parse().expect("couldn't parse address string");
+        let config = Config {
+            read_budget: 2,
+            ..Config::default()
+        };
+        let sd = ::bootstrap_with_config(addr, config, &HANDLER).expect("couldn't start server");
+
+        // one write carrying far more frames than a single readiness event may take, nothing
+        // else arrives afterwards to trigger another event
+        let mut batch = vec!{};
+        for i in 0..50 {
+            let msg = format!("msg{:02}", i);
+            let mut len_buf = [0u8; 8];
+            BigEndian::write_u64(&mut len_buf, msg.len() as u64);
+            batch.extend_from_slice(&len_buf);
+            batch.extend_from_slice(msg.as_bytes());
+        }
+        let mut sock = connect(addr);
+        sock.write_all(&batch).expect("couldn't write batch");
+
+        // batches go to different workers so replies may come back in any order
+        let mut replies: Vec<String> = (0..50).map(|_| read_string(&mut sock)).collect();
+        replies.sort();
+        let mut expected: Vec<String> = (0..50).map(|i| format!("msg{:02}", i).chars().rev().collect()).collect();
+        expected.sort();
+        assert_eq!(replies, expected);
+        sd.shutdown().expect("couldn't shut down");
+    }
+
+    struct Echo{}
+
+    impl MessageHandler for Echo {
+        type Req = Bytes;
+        type Resp = Bytes;
+
+        fn process(&self, msg: Bytes) -> Result<Bytes> {
+            Ok(msg)
+        }
+
+        fn serialize(&self, msg: Bytes) -> Result<Bytes> {
+            Ok(msg)
+        }
+
+        fn deserialize(&self, buf: Bytes) -> Result<Bytes> {
+            Ok(buf)
+        }
+    }
+
+    static ECHO: Echo = Echo{};
+
+    #[test]
+    fn requests_barely_allocate() {
+        const BATCH: usize = 100;
+        const WARMUP: usize = 10;
+        const ROUNDS: usize = 200;
+
+        let (_source, sink) = worker::write_pipeline();
+        let processor = Processor::new(Arc::new(Unary::new(&ECHO)), Publisher::new(sink, false),
+            Arc::new(Metrics::new(0)), false, None, Some(Duration::from_secs(60)));
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("couldn't bind");
+        let mut client = TcpStream::connect(listener.local_addr().expect("no local address"))
+            .expect("couldn't connect");
+        let (sock, _) = listener.accept().expect("couldn't accept");
+        let sock = MioTcpStream::from_stream(sock).expect("couldn't convert socket");
+        sock.set_nodelay(true).expect("couldn't set nodelay");
+        client.set_nodelay(true).expect("couldn't set nodelay");
+        let id = ConnId::new(0, 0, 0);
+        let mut conn = Connection::new(sock, id, Token(0));
+        let span = ConnSpan::new(id, None).request();
+        let info = Arc::new(ConnInfo::new(None));
+        let mut pool = BufferPool::new(64 * 1024, 16);
+
+        let mut batch = vec!{};
+        for i in 0..BATCH {
+            let msg = format!("request number {:04}", i);
+            let mut len_buf = [0u8; 8];
+            BigEndian::write_u64(&mut len_buf, msg.len() as u64);
+            batch.extend_from_slice(&len_buf);
+            batch.extend_from_slice(msg.as_bytes());
+        }
+        let mut echoed = vec![0u8; batch.len()];
+        let mut msgs = Vec::with_capacity(BATCH);
+        let mut replies: Vec<MsgBuf> = Vec::with_capacity(BATCH);
+
+        // read, handle and write back the same way the I/O thread does when running to completion
+        for round in 0..ROUNDS {
+            if round == WARMUP {
+                count_allocations(true);
+            }
+
+            client.write_all(&batch).expect("couldn't write batch");
+            while msgs.len() < BATCH {
+                conn.read_messages(&mut pool, usize::MAX, &mut msgs).expect("couldn't read");
+            }
+            for buf in msgs.drain(..) {
+                let req = Request::new(MsgBuf::new(id, buf), Instant::now(), span.clone(), info.clone());
+                match processor.start(req, &mut replies) {
+                    Progress::Done(true) => {},
+                    _ => panic!("request wasn't handled"),
+                }
+            }
+            for reply in replies.drain(..) {
+                conn.send_message(reply.buf).expect("couldn't queue reply");
+            }
+            conn.flush().expect("couldn't flush");
+
+            client.read_exact(&mut echoed).expect("couldn't read replies");
+            assert_eq!(echoed, batch);
+        }
+
+        // every request gets its own boxed span when tracing is compiled in
+        let per_request = if cfg!(feature = "tracing") { 1 } else { 0 };
+        let requests = ((ROUNDS - WARMUP) * BATCH) as u64;
+        let allocations = count_allocations(false) - per_request * requests;
+        assert!(allocations * 100 < requests, "{} allocations for {} requests", allocations, requests);
+    }
+
+    #[test]
+    fn stats_track_requests() {
+        let addr: SocketAddr = "127.0.0.1:7873".parse().expect("couldn't parse address string");
+        let sd = ::bootstrap(addr, 3, &HANDLER).expect("couldn't start server");
+
+        let mut sock = connect(addr);
+        for i in 0..10 {
+            assert_eq!(request(&mut sock, &format!("abc{}", i)), format!("{}cba", i));
+        }
+        // not utf8, so deserialize fails and no reply is sent
+        send_frame(&mut sock, &[0xff, 0xfe]);
+        assert_eq!(request(&mut sock, "xyz"), "zyx");
+
+        let stats = sd.stats();
+        assert_eq!(stats.connections_accepted, 1);
+        assert_eq!(stats.active_connections, 1);
+        assert_eq!(stats.frames_in, 12);
+        assert_eq!(stats.frames_out, 11);
+        assert_eq!(stats.bytes_in, 10 * 12 + 10 + 11);
+        assert_eq!(stats.bytes_out, 10 * 12 + 11);
+        assert_eq!(stats.deserialize.count, 12);
+        assert_eq!(stats.process.count, 11);
+        assert_eq!(stats.queue_wait.count, 12);
+        assert_eq!(stats.errors("deserialize"), 1);
+        assert_eq!(stats.worker_queue_depths, vec![0, 0]);
+
+        drop(sock);
+        for _ in 0..50 {
+            if sd.stats().connections_closed == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let stats = sd.stats();
+        assert_eq!(stats.connections_closed, 1);
+        assert_eq!(stats.active_connections, 0);
+        sd.shutdown().expect("couldn't shut down");
+    }
+
+    fn http_get(addr: SocketAddr, path: &str) -> (String, String) {
+        http_request(addr, "GET", path)
+    }
+
+    fn http_request(addr: SocketAddr, method: &str, path: &str) -> (String, String) {
+        let mut sock = connect(addr);
+        write!(sock, "{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path).expect("couldn't send request");
+        let mut response = String::new();
+        sock.read_to_string(&mut response).expect("couldn't read response");
+
+        let mut parts = response.splitn(2, "\r\n\r\n");
+        let head = parts.next().unwrap_or("");
+        let status = head.lines().next().unwrap_or("").to_owned();
+        (status, parts.next().unwrap_or("").to_owned())
+    }
+
+    #[test]
+    fn admin_endpoints() {
+        let addr: SocketAddr = "127.0.0.1:7874".parse().expect("couldn't parse address string");
+        let config = Config {
+            admin_addr: Some("127.0.0.1:0".parse().expect("couldn't parse address string")),
+            ..Config::default()
+        };
+        let sd = ::bootstrap_with_config(addr, config, &HANDLER).expect("couldn't start server");
+        let admin = sd.admin_addr().expect("no admin address");
+
+        let mut sock = connect(addr);
+        assert_eq!(request(&mut sock, "abc"), "cba");
+
+        let (status, body) = http_get(admin, "/metrics");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body.lines().any(|line| line == "tcp_service_connections_accepted_total 1"));
+        assert!(body.lines().any(|line| line == "tcp_service_stage_duration_seconds_count{stage=\"process\"} 1"));
+
+        assert_eq!(http_get(admin, "/healthz").0, "HTTP/1.1 200 OK");
+        assert_eq!(http_get(admin, "/readyz").0, "HTTP/1.1 200 OK");
+        assert_eq!(http_get(admin, "/nope").0, "HTTP/1.1 404 Not Found");
+
+        sd.drain();
+        assert_eq!(http_get(admin, "/readyz"), ("HTTP/1.1 503 Service Unavailable".to_owned(), "draining\n".to_owned()));
+        assert_eq!(http_get(admin, "/healthz").0, "HTTP/1.1 200 OK");
+        // still serving existing connections while draining
+        assert_eq!(request(&mut sock, "xyz"), "zyx");
+
+        sd.shutdown().expect("couldn't shut down");
+    }
+
+    #[test]
+    fn request_logs() {
+        let addr: SocketAddr = "127.0.0.1:7875".parse().expect("couldn't parse address string");
+        let dir = ::std::env::temp_dir();
+        let access_path = dir.join(format!("tcp_service_access_{}.log", ::std::process::id()));
+        let slow_path = dir.join(format!("tcp_service_slow_{}.log", ::std::process::id()));
+        let _ = fs::remove_file(&access_path);
+        let _ = fs::remove_file(&slow_path);
+
+        let config = Config {
+            access_log: Some(LogConfig { target: LogTarget::File(access_path.clone()), format: LogFormat::Text }),
+            slow_log: Some(LogConfig { target: LogTarget::File(slow_path.clone()), format: LogFormat::Json }),
+            slow_threshold: Duration::from_secs(0),
+            ..Config::default()
+        };
+        let sd = ::bootstrap_with_config(addr, config, &HANDLER).expect("couldn't start server");
+
+        let mut sock = connect(addr);
+        assert_eq!(request(&mut sock, "abc"), "cba");
+        send_frame(&mut sock, &[0xff, 0xfe]);
+        assert_eq!(request(&mut sock, "hello"), "olleh");
+
+        // the writers run on their own threads and flush once they have caught up
+        let read_lines = |path| {
+            for _ in 0..100 {
+                let text = fs::read_to_string(path).unwrap_or_default();
+                if text.lines().count() == 3 {
+                    return text;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+            panic!("request log never got three lines");
+        };
+
+        let access = read_lines(&access_path);
+        let peer = format!("peer={}", sock.local_addr().expect("no local address"));
+        let lines: Vec<&str> = access.lines().collect();
+        assert!(lines[0].contains(&peer));
+        assert!(lines[0].contains(" req_bytes=3 resp_bytes=3 "));
+        assert!(lines[0].ends_with(" outcome=ok"));
+        assert!(lines[1].ends_with(" outcome=deserialize"));
+        assert!(lines[2].contains(" req_bytes=5 resp_bytes=5 "));
+
+        let slow = read_lines(&slow_path);
+        assert!(slow.lines().all(|line| line.starts_with("{\"time\":") && line.contains("\"stages\":{\"queue_us\":")));
+
+        sd.shutdown().expect("couldn't shut down");
+        let _ = fs::remove_file(&access_path);
+        let _ = fs::remove_file(&slow_path);
+    }
+
+    fn closed_by_server(sock: &mut TcpStream) -> bool {
+        let mut buf = [0u8; 1];
+        match sock.read(&mut buf) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(e) => e.kind() == ::std::io::ErrorKind::ConnectionReset,
+        }
+    }
+
+    #[test]
+    fn list_and_close_connections() {
+        let addr: SocketAddr = "127.0.0.1:7876".parse().expect("couldn't parse address string");
+        let config = Config {
+            io_threads: 2,
+            admin_addr: Some("127.0.0.1:0".parse().expect("couldn't parse address string")),
+            ..Config::default()
+        };
+        let sd = ::bootstrap_with_config(addr, config, &HANDLER).expect("couldn't start server");
+        let admin = sd.admin_addr().expect("no admin address");
+
+        let mut socks: Vec<TcpStream> = (0..4).map(|_| connect(addr)).collect();
+        for sock in &mut socks {
+            assert_eq!(request(sock, "abc"), "cba");
+        }
+
+        let conns = sd.connections().expect("couldn't list connections");
+        assert_eq!(conns.len(), 4);
+        for conn in &conns {
+            assert_eq!((conn.bytes_in, conn.bytes_out, conn.queued_bytes), (11, 11, 0));
+            assert!(conn.worker.is_some());
+        }
+
+        let peer_of = |sock: &TcpStream| sock.local_addr().expect("no local address");
+        let first = conns.iter().find(|conn| conn.peer == Some(peer_of(&socks[0]))).expect("first client not listed");
+        assert!(sd.close_connection(first.id).expect("couldn't close"));
+        assert!(!sd.close_connection(first.id).expect("couldn't close"));
+        assert!(closed_by_server(&mut socks[0]));
+
+        let (status, body) = http_get(admin, "/connections");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body.lines().count(), 3);
+        let second = conns.iter().find(|conn| conn.peer == Some(peer_of(&socks[1]))).expect("second client not listed");
+        assert!(body.contains(&format!("id={} peer={} ", second.id, peer_of(&socks[1]))));
+
+        assert_eq!(http_request(admin, "POST", &format!("/connections/close?id={}", second.id)),
+            ("HTTP/1.1 200 OK".to_owned(), "closed 1\n".to_owned()));
+        assert!(closed_by_server(&mut socks[1]));
+
+        assert_eq!(http_request(admin, "POST", "/connections/close?peer=127.0.0.1"),
+            ("HTTP/1.1 200 OK".to_owned(), "closed 2\n".to_owned()));
+        assert!(closed_by_server(&mut socks[2]));
+        assert!(closed_by_server(&mut socks[3]));
+        assert!(sd.connections().expect("couldn't list connections").is_empty());
+
+        sd.shutdown().expect("couldn't shut down");
+    }
+
+    fn tagged(tag: u16, msg: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; 2];
+        BigEndian::write_u16(&mut buf, tag);
+        buf.extend_from_slice(msg);
+        buf
+    }
+
+    #[test]
+    fn routes_by_type_tag() {
+        let addr: SocketAddr = "127.0.0.1:7877".parse().expect("couldn't parse address string");
+        let router = Router::new()
+            .route(1, &HANDLER)
+            .route(2, &ECHO)
+            .route_streaming(3, &COUNTER);
+        let sd = ::bootstrap_router(addr, Config::default(), router).expect("couldn't start server");
+
+        let mut sock = connect(addr);
+        send_frame(&mut sock, &tagged(1, b"abc"));
+        assert_eq!(read_string(&mut sock), "cba");
+        send_frame(&mut sock, &tagged(2, b"abc"));
+        assert_eq!(read_string(&mut sock), "abc");
+
+        let mut count = [0u8; 8];
+        BigEndian::write_u64(&mut count, 2);
+        send_frame(&mut sock, &tagged(3, &count));
+        assert_eq!(BigEndian::read_u64(&read_frame(&mut sock)), 0);
+        assert_eq!(BigEndian::read_u64(&read_frame(&mut sock)), 1);
+
+        // neither an unknown tag nor a frame too short for one gets a reply
+        send_frame(&mut sock, &tagged(9, b"abc"));
+        send_frame(&mut sock, &[1]);
+        send_frame(&mut sock, &tagged(1, b"xyz"));
+        assert_eq!(read_string(&mut sock), "zyx");
+        assert_eq!(sd.stats().errors("deserialize"), 2);
+
+        sd.shutdown().expect("couldn't shut down");
+    }
+
+    struct Prefixer {
+        prefix: String,
+    }
+
+    impl MessageHandler for Prefixer {
+        type Req = Bytes;
+        type Resp = String;
+
+        fn process(&self, msg: Bytes) -> Result<String> {
+            Ok(format!("{}{}", self.prefix, String::from_utf8_lossy(&msg)))
+        }
+
+        fn serialize(&self, msg: String) -> Result<Bytes> {
+            Ok(msg.into())
+        }
+
+        fn deserialize(&self, buf: Bytes) -> Result<Bytes> {
+            Ok(buf)
+        }
+    }
+
+    // Numbers the requests it sees, which only works if no other thread shares it.
+    struct Numberer {
+        worker: usize,
+        seen: u64,
+    }
+
+    impl StatefulMessageHandler for Numberer {
+        type Req = Bytes;
+        type Resp = String;
+
+        fn process(&mut self, msg: Bytes, _ctx: &Context) -> Result<String> {
+            self.seen += 1;
+            Ok(format!("{}/{} {}", self.worker, self.seen, String::from_utf8_lossy(&msg)))
+        }
+
+        fn serialize(&mut self, msg: String) -> Result<Bytes> {
+            Ok(msg.into())
+        }
+
+        fn deserialize(&mut self, buf: Bytes) -> Result<Bytes> {
+            Ok(buf)
+        }
+    }
+
+    #[test]
+    fn shared_and_per_worker_handlers() {
+        let addr: SocketAddr = "127.0.0.1:7878".parse().expect("couldn't parse address string");
+        let handler = Arc::new(Prefixer { prefix: "> ".to_owned() });
+        let sd = ::bootstrap_shared(addr, Config::default(), handler.clone()).expect("couldn't start server");
+        let mut sock = connect(addr);
+        send_frame(&mut sock, b"abc");
+        assert_eq!(read_string(&mut sock), "> abc");
+        sd.shutdown().expect("couldn't shut down");
+
+        let addr: SocketAddr = "127.0.0.1:7879".parse().expect("couldn't parse address string");
+        let config = Config {
+            mode: Mode::RunToCompletion,
+            ..Config::default()
+        };
+        let sd = ::bootstrap_per_worker(addr, config, |worker| Numberer { worker, seen: 0 })
+            .expect("couldn't start server");
+        let mut sock = connect(addr);
+        for n in 1..4 {
+            send_frame(&mut sock, b"abc");
+            assert_eq!(read_string(&mut sock), format!("0/{} abc", n));
+        }
+        sd.shutdown().expect("couldn't shut down");
+    }
+
+    struct Patient;
+
+    impl MessageHandler for Patient {
+        type Req = Bytes;
+        type Resp = Bytes;
+
+        fn process(&self, msg: Bytes) -> Result<Bytes> {
+            Ok(msg)
+        }
+
+        fn process_with_context(&self, msg: Bytes, ctx: &Context) -> Result<Bytes> {
+            match &msg[..] {
+                b"slow" => thread::sleep(Duration::from_millis(100)),
+                b"wait" => {
+                    let start = Instant::now();
+                    while !ctx.is_cancelled() {
+                        assert!(start.elapsed() < Duration::from_secs(5), "never cancelled");
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    return Err("client went away".into());
+                },
+                _ => {},
+            }
+            Ok(msg)
+        }
+
+        fn serialize(&self, msg: Bytes) -> Result<Bytes> {
+            Ok(msg)
+        }
+
+        fn deserialize(&self, buf: Bytes) -> Result<Bytes> {
+            Ok(buf)
+        }
+    }
+
+    static PATIENT: Patient = Patient;
+
+    // "wait" holds its thread until "open" comes in, which in turn only answers once something
+    // is waiting. Both finish only if they run at the same time.
+    #[cfg(feature = "tokio")]
+    struct Gate {
+        waiting: AtomicBool,
+        opened: AtomicBool,
+    }
+
+    #[cfg(feature = "tokio")]
+    impl MessageHandler for Gate {
+        type Req = Bytes;
+        type Resp = Bytes;
+
+        fn process(&self, msg: Bytes) -> Result<Bytes> {
+            let (mine, other) = match &msg[..] {
+                b"wait" => (&self.waiting, &self.opened),
+                b"open" => (&self.opened, &self.waiting),
+                _ => return Err("unknown command".into()),
+            };
+            mine.store(true, Ordering::Release);
+            for _ in 0..250 {
+                if other.load(Ordering::Acquire) {
+                    return Ok(msg);
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+            Ok(Bytes::from_static(b"timed out"))
+        }
+
+        fn serialize(&self, msg: Bytes) -> Result<Bytes> {
+            Ok(msg)
+        }
+
+        fn deserialize(&self, buf: Bytes) -> Result<Bytes> {
+            Ok(buf)
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    static GATE: Gate = Gate { waiting: AtomicBool::new(false), opened: AtomicBool::new(false) };
+
+    fn wait_for_error(sd: &::Shutdown, kind: &str) {
+        for _ in 0..100 {
+            if sd.stats().errors(kind) == 1 {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("no {} request", kind);
+    }
+
+    #[test]
+    fn drops_expired_and_cancelled_requests() {
+        let addr: SocketAddr = "127.0.0.1:7880".parse().expect("couldn't parse address string");
+        let config = Config {
+            num_workers: 2,
+            request_timeout: Some(Duration::from_millis(50)),
+            ..Config::default()
+        };
+        let sd = ::bootstrap_with_config(addr, config, &PATIENT).expect("couldn't start server");
+
+        // the only worker is busy for longer than the second request may wait
+        let mut sock = connect(addr);
+        send_frame(&mut sock, b"slow");
+        send_frame(&mut sock, b"late");
+        assert_eq!(read_string(&mut sock), "slow");
+        assert_eq!(request(&mut sock, "fast"), "fast");
+        assert_eq!(sd.stats().errors("expired"), 1);
+        sd.shutdown().expect("couldn't shut down");
+
+        // correlated clients still see the end of an expired request's stream
+        let addr: SocketAddr = "127.0.0.1:7884".parse().expect("couldn't parse address string");
+        let config = Config {
+            num_workers: 2,
+            correlation: true,
+            request_timeout: Some(Duration::from_millis(50)),
+            ..Config::default()
+        };
+        let sd = ::bootstrap_with_config(addr, config, &PATIENT).expect("couldn't start server");
+        let mut sock = connect(addr);
+        for (id, msg) in [(1, "slow"), (2, "late")].iter() {
+            send_frame(&mut sock, &correlated(*id, msg.as_bytes()));
+        }
+        assert_eq!(read_response(&mut sock), (1, 1, b"slow".to_vec()));
+        assert_eq!(read_response(&mut sock), (2, 1, vec!{}));
+        assert_eq!(sd.stats().errors("expired"), 1);
+        sd.shutdown().expect("couldn't shut down");
+
+        let addr: SocketAddr = "127.0.0.1:7881".parse().expect("couldn't parse address string");
+        let sd = ::bootstrap_with_config(addr, Config::default(), &PATIENT).expect("couldn't start server");
+        let mut sock = connect(addr);
+        send_frame(&mut sock, b"wait");
+        assert_eq!(request(&mut connect(addr), "fast"), "fast");
+        drop(sock);
+        wait_for_error(&sd, "cancelled");
+        assert_eq!(sd.stats().errors("process"), 0);
+        sd.shutdown().expect("couldn't shut down");
+    }
+
+    // Answers after a delay, woken by a thread of its own like a client library would.
+    #[cfg(feature = "tokio")]
+    struct Delay {
+        msg: Option<Bytes>,
+        delay: Duration,
+        done: Arc<AtomicBool>,
+        started: bool,
+    }
+
+    #[cfg(feature = "tokio")]
+    impl Future for Delay {
+        type Output = Result<Bytes>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Result<Bytes>> {
+            if self.done.load(Ordering::Acquire) {
+                return Poll::Ready(Ok(self.msg.take().expect("polled after completion")));
+            }
+            if !self.started {
+                self.started = true;
+                let (done, delay, waker) = (self.done.clone(), self.delay, cx.waker().clone());
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    done.store(true, Ordering::Release);
+                    waker.wake();
+                });
+            }
+            Poll::Pending
+        }
+    }
+
+    // Never answers.
+    #[cfg(feature = "tokio")]
+    struct Stalled;
+
+    #[cfg(feature = "tokio")]
+    impl AsyncMessageHandler for Stalled {
+        type Req = Bytes;
+        type Resp = Bytes;
+
+        fn process(&self, _msg: Bytes, _ctx: AsyncContext) -> ResponseFuture<Bytes> {
+            Box::pin(::std::future::pending())
+        }
+
+        fn serialize(&self, msg: Bytes) -> Result<Bytes> {
+            Ok(msg)
+        }
+
+        fn deserialize(&self, buf: Bytes) -> Result<Bytes> {
+            Ok(buf)
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    struct Waiter;
+
+    #[cfg(feature = "tokio")]
+    impl AsyncMessageHandler for Waiter {
+        type Req = Bytes;
+        type Resp = Bytes;
+
+        fn process(&self, msg: Bytes, _ctx: AsyncContext) -> ResponseFuture<Bytes> {
+            Box::pin(Delay {
+                msg: Some(msg),
+                delay: Duration::from_millis(100),
+                done: Arc::new(AtomicBool::new(false)),
+                started: false,
+            })
+        }
+
+        fn serialize(&self, msg: Bytes) -> Result<Bytes> {
+            Ok(msg)
+        }
+
+        fn deserialize(&self, buf: Bytes) -> Result<Bytes> {
+            Ok(buf)
+        }
+    }
+
+    // Answers each request only once `expected` of them have arrived, so requests handled one
+    // after another never get an answer. The last to arrive wakes the others from a thread of
+    // its own, like a client library would.
+    struct Rendezvous {
+        expected: usize,
+        arrived: Arc<Mutex<(usize, Vec<task::Waker>)>>,
+    }
+
+    impl Rendezvous {
+        fn new(expected: usize) -> Rendezvous {
+            Rendezvous {
+                expected,
+                arrived: Arc::new(Mutex::new((0, vec!{}))),
+            }
+        }
+    }
+
+    impl AsyncMessageHandler for Rendezvous {
+        type Req = Bytes;
+        type Resp = Bytes;
+
+        fn process(&self, msg: Bytes, _ctx: AsyncContext) -> ResponseFuture<Bytes> {
+            Box::pin(Arrival {
+                msg: Some(msg),
+                expected: self.expected,
+                arrived: self.arrived.clone(),
+                counted: false,
+            })
+        }
+
+        fn serialize(&self, msg: Bytes) -> Result<Bytes> {
+            Ok(msg)
+        }
+
+        fn deserialize(&self, buf: Bytes) -> Result<Bytes> {
+            Ok(buf)
+        }
+    }
+
+    struct Arrival {
+        msg: Option<Bytes>,
+        expected: usize,
+        arrived: Arc<Mutex<(usize, Vec<task::Waker>)>>,
+        counted: bool,
+    }
+
+    impl Future for Arrival {
+        type Output = Result<Bytes>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Result<Bytes>> {
+            let shared = self.arrived.clone();
+            let mut arrived = shared.lock().expect("lock poisoned");
+            if !self.counted {
+                self.counted = true;
+                arrived.0 += 1;
+            }
+            if arrived.0 < self.expected {
+                arrived.1.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let waiting: Vec<task::Waker> = arrived.1.drain(..).collect();
+            thread::spawn(move || {
+                for waker in waiting {
+                    waker.wake();
+                }
+            });
+            Poll::Ready(Ok(self.msg.take().expect("polled after completion")))
+        }
+    }
+
+    #[test]
+    fn async_requests_overlap() {
+        let addr: SocketAddr = "127.0.0.1:7882".parse().expect("couldn't parse address string");
+        let config = Config {
+            num_workers: 2,
+            ..Config::default()
+        };
+        let sd = ::bootstrap_async(addr, config, Rendezvous::new(20)).expect("couldn't start server");
+
+        // twenty requests on a single worker are all waiting at once
+        let mut sock = connect(addr);
+        for i in 0..20 {
+            send_frame(&mut sock, format!("{:02}", i).as_bytes());
+        }
+        let mut replies: Vec<String> = (0..20).map(|_| read_string(&mut sock)).collect();
+        replies.sort();
+        assert_eq!(replies, (0..20).map(|i| format!("{:02}", i)).collect::<Vec<_>>());
+        assert_eq!(sd.stats().process.count, 20);
+        sd.shutdown().expect("couldn't shut down");
+
+        let addr: SocketAddr = "127.0.0.1:7883".parse().expect("couldn't parse address string");
+        let config = Config {
+            mode: Mode::RunToCompletion,
+            ..Config::default()
+        };
+        let sd = ::bootstrap_async(addr, config, Rendezvous::new(2)).expect("couldn't start server");
+
+        // the I/O thread keeps serving other connections while a future waits
+        let mut socks: Vec<TcpStream> = (0..2).map(|_| connect(addr)).collect();
+        for (sock, msg) in socks.iter_mut().zip(&["abc", "def"]) {
+            send_frame(sock, msg.as_bytes());
+        }
+        assert_eq!(read_string(&mut socks[0]), "abc");
+        assert_eq!(read_string(&mut socks[1]), "def");
+        assert_eq!(sd.stats().process.count, 2);
+        sd.shutdown().expect("couldn't shut down");
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn tokio_backend() {
+        use tokio::runtime::Builder;
+        use tokio::net::TcpListener as TokioListener;
+        use tokio::sync::oneshot;
+
+        let rt = Builder::new_current_thread().enable_all().build().expect("couldn't build runtime");
+        let listen = |rt: &::tokio::runtime::Runtime| {
+            let _guard = rt.enter();
+            let listener = TcpListener::bind("127.0.0.1:0").expect("couldn't bind");
+            listener.set_nonblocking(true).expect("couldn't set nonblocking");
+            TokioListener::from_std(listener).expect("couldn't register listener")
+        };
+
+        let (sync, async_, stalled, gate, notifier, relay) = {
+            let _guard = rt.enter();
+            let config = Config {
+                correlation: true,
+                ..Config::default()
+            };
+            (::bootstrap_tokio(listen(&rt), Config::default(), &HANDLER).expect("couldn't start server"),
+                ::bootstrap_tokio_async(listen(&rt), config, Waiter).expect("couldn't start server"),
+                ::bootstrap_tokio_async(listen(&rt), Config::default(), Stalled).expect("couldn't start server"),
+                ::bootstrap_tokio(listen(&rt), Config::default(), &GATE).expect("couldn't start server"),
+                ::bootstrap_tokio(listen(&rt), Config::default(), &NOTIFIER).expect("couldn't start server"),
+                ::bootstrap_tokio_streaming(listen(&rt), Config::default(), &TOKIO_RELAY).expect("couldn't start server"))
+        };
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let runtime = thread::spawn(move || rt.block_on(stop_rx));
+
+        // empty frames are skipped, as on the mio backend
+        let mut sync_sock = connect(sync.local_addr());
+        for msg in &["", "abc", "hello"] {
+            send_frame(&mut sync_sock, msg.as_bytes());
+        }
+        assert_eq!(read_string(&mut sync_sock), "cba");
+        assert_eq!(read_string(&mut sync_sock), "olleh");
+        assert_eq!(sync.stats().frames_in, 2);
+        assert_eq!(sync.stats().frames_out, 2);
+
+        let mut sock = connect(async_.local_addr());
+        send_frame(&mut sock, &correlated(42, b"abc"));
+        let reply = read_frame(&mut sock);
+        assert_eq!(BigEndian::read_u64(&reply[..8]), 42);
+        assert_eq!(&reply[8..], b"\x01abc");
+
+        // a peer hanging up is noticed while its request is still pending
+        let mut sock = connect(stalled.local_addr());
+        send_frame(&mut sock, b"abc");
+        drop(sock);
+        for _ in 0..100 {
+            if stalled.stats().connections_closed == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(stalled.stats().active_connections, 0);
+
+        // a handler waiting on the blocking pool doesn't hold up the runtime's only thread
+        let mut waiting = connect(gate.local_addr());
+        send_frame(&mut waiting, b"wait");
+        assert_eq!(request(&mut connect(gate.local_addr()), "open"), "open");
+        assert_eq!(read_string(&mut waiting), "wait");
+
+        // streamed responses are written while the handler carries on
+        let mut sock = connect(relay.local_addr());
+        send_frame(&mut sock, b"go");
+        assert_eq!(read_string(&mut sock), "first");
+        TOKIO_RELAY.received.store(true, Ordering::Release);
+        assert_eq!(read_string(&mut sock), "second");
+
+        // published messages reach the subscribers' connection tasks
+        let mut socks: Vec<TcpStream> = (0..2).map(|_| connect(notifier.local_addr())).collect();
+        for sock in &mut socks {
+            assert_eq!(request(sock, "sub news"), "ok");
+        }
+        send_frame(&mut socks[0], b"pub news hi");
+        let mut replies = vec![read_string(&mut socks[0]), read_string(&mut socks[0])];
+        replies.sort();
+        assert_eq!(replies, vec!["hi", "ok"]);
+        assert_eq!(read_string(&mut socks[1]), "hi");
+        notifier.publisher().broadcast("all").expect("couldn't broadcast");
+        for sock in &mut socks {
+            assert_eq!(read_string(sock), "all");
+        }
+
+        // shutting down closes the connections that are still open
+        sync.shutdown();
+        assert!(closed_by_server(&mut sync_sock));
+        async_.shutdown();
+        stalled.shutdown();
+        gate.shutdown();
+        notifier.shutdown();
+        relay.shutdown();
+        stop_tx.send(()).expect("runtime already stopped");
+        runtime.join().expect("runtime panicked").expect("runtime stopped early");
+    }
+}
@@ -0,0 +1,317 @@
+use std::sync::atomic::{AtomicU64, AtomicI64, Ordering};
+use std::time::Duration;
+
+// Histogram buckets are powers of two in microseconds, 1us up to about 16s, plus one for
+// everything slower.
+const HISTOGRAM_BUCKETS: usize = 26;
+
+#[derive(Debug, Default)]
+pub struct Counter {
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge {
+    value: AtomicI64,
+}
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: (0..HISTOGRAM_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros());
+        self.buckets[bucket(micros)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let buckets = self.buckets.iter().enumerate()
+            .map(|(i, count)| (bucket_bound(i), count.load(Ordering::Relaxed)))
+            .collect();
+
+        HistogramSnapshot {
+            count: self.count.load(Ordering::Relaxed),
+            sum: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)),
+            buckets,
+        }
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+fn bucket(micros: u64) -> usize {
+    if micros <= 1 {
+        return 0;
+    }
+    let idx = 64 - (micros - 1).leading_zeros() as usize;
+    if idx >= HISTOGRAM_BUCKETS { HISTOGRAM_BUCKETS - 1 } else { idx }
+}
+
+// Upper bound of a bucket in microseconds, None for the last one which has no bound.
+fn bucket_bound(idx: usize) -> Option<u64> {
+    if idx == HISTOGRAM_BUCKETS - 1 {
+        None
+    } else {
+        Some(1 << idx)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub sum: Duration,
+    // (upper bound in microseconds, samples in this bucket), not cumulative
+    pub buckets: Vec<(Option<u64>, u64)>,
+}
+
+impl HistogramSnapshot {
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        let nanos = self.sum.as_nanos() / u128::from(self.count);
+        Duration::new((nanos / 1_000_000_000) as u64, (nanos % 1_000_000_000) as u32)
+    }
+
+    // Upper bound of the bucket holding the given quantile, so it overestimates by at most
+    // a factor of two.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = (q * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for &(bound, count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                return bound.map(Duration::from_micros);
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Accept,
+    Read,
+    Write,
+    Deserialize,
+    Process,
+    Serialize,
+    Dispatch,
+    StaleResponse,
+    // skipped because the client disconnected or the deadline passed first
+    Cancelled,
+    Expired,
+}
+
+impl Failure {
+    pub const ALL: [Failure; 10] = [Failure::Accept, Failure::Read, Failure::Write, Failure::Deserialize,
+        Failure::Process, Failure::Serialize, Failure::Dispatch, Failure::StaleResponse, Failure::Cancelled,
+        Failure::Expired];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Failure::Accept => "accept",
+            Failure::Read => "read",
+            Failure::Write => "write",
+            Failure::Deserialize => "deserialize",
+            Failure::Process => "process",
+            Failure::Serialize => "serialize",
+            Failure::Dispatch => "dispatch",
+            Failure::StaleResponse => "stale_response",
+            Failure::Cancelled => "cancelled",
+            Failure::Expired => "expired",
+        }
+    }
+}
+
+// Shared by every I/O thread and worker. Everything is a relaxed atomic, a snapshot is only
+// consistent per value, not across values.
+#[derive(Debug)]
+pub struct Metrics {
+    pub connections_accepted: Counter,
+    pub connections_closed: Counter,
+    pub active_connections: Gauge,
+    pub bytes_in: Counter,
+    pub bytes_out: Counter,
+    pub frames_in: Counter,
+    pub frames_out: Counter,
+    pub queue_wait: Histogram,
+    pub deserialize: Histogram,
+    pub process: Histogram,
+    pub serialize: Histogram,
+    pub write: Histogram,
+    pub worker_queues: Vec<Gauge>,
+    errors: Vec<Counter>,
+}
+
+impl Metrics {
+    pub fn new(num_workers: usize) -> Metrics {
+        Metrics {
+            connections_accepted: Counter::default(),
+            connections_closed: Counter::default(),
+            active_connections: Gauge::default(),
+            bytes_in: Counter::default(),
+            bytes_out: Counter::default(),
+            frames_in: Counter::default(),
+            frames_out: Counter::default(),
+            queue_wait: Histogram::new(),
+            deserialize: Histogram::new(),
+            process: Histogram::new(),
+            serialize: Histogram::new(),
+            write: Histogram::new(),
+            worker_queues: (0..num_workers).map(|_| Gauge::default()).collect(),
+            errors: Failure::ALL.iter().map(|_| Counter::default()).collect(),
+        }
+    }
+
+    pub fn error(&self, failure: Failure) {
+        self.errors[failure as usize].inc();
+    }
+
+    pub fn errors(&self, failure: Failure) -> u64 {
+        self.errors[failure as usize].get()
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            connections_accepted: self.connections_accepted.get(),
+            connections_closed: self.connections_closed.get(),
+            active_connections: self.active_connections.get(),
+            bytes_in: self.bytes_in.get(),
+            bytes_out: self.bytes_out.get(),
+            frames_in: self.frames_in.get(),
+            frames_out: self.frames_out.get(),
+            queue_wait: self.queue_wait.snapshot(),
+            deserialize: self.deserialize.snapshot(),
+            process: self.process.snapshot(),
+            serialize: self.serialize.snapshot(),
+            write: self.write.snapshot(),
+            worker_queue_depths: self.worker_queues.iter().map(Gauge::get).collect(),
+            errors: Failure::ALL.iter().map(|f| (f.name(), self.errors(*f))).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub connections_accepted: u64,
+    pub connections_closed: u64,
+    pub active_connections: i64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub frames_in: u64,
+    pub frames_out: u64,
+    pub queue_wait: HistogramSnapshot,
+    pub deserialize: HistogramSnapshot,
+    pub process: HistogramSnapshot,
+    pub serialize: HistogramSnapshot,
+    pub write: HistogramSnapshot,
+    pub worker_queue_depths: Vec<i64>,
+    pub errors: Vec<(&'static str, u64)>,
+}
+
+impl Stats {
+    pub fn errors(&self, kind: &str) -> u64 {
+        self.errors.iter().find(|&&(name, _)| name == kind).map(|&(_, n)| n).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{Histogram, HistogramSnapshot, Metrics, Failure, bucket, HISTOGRAM_BUCKETS};
+
+    #[test]
+    fn histogram_buckets_and_quantiles() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1), 0);
+        assert_eq!(bucket(2), 1);
+        assert_eq!(bucket(3), 2);
+        assert_eq!(bucket(1024), 10);
+        assert_eq!(bucket(1025), 11);
+        assert_eq!(bucket(u64::MAX), HISTOGRAM_BUCKETS - 1);
+
+        let histogram = Histogram::new();
+        for _ in 0..90 {
+            histogram.record(Duration::from_micros(3));
+        }
+        for _ in 0..10 {
+            histogram.record(Duration::from_millis(5));
+        }
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 100);
+        assert_eq!(snapshot.quantile(0.5), Some(Duration::from_micros(4)));
+        assert_eq!(snapshot.quantile(0.99), Some(Duration::from_micros(8192)));
+        assert_eq!(snapshot.mean(), Duration::from_nanos(502_700));
+
+        // counts past u32::MAX must not wrap
+        let many = HistogramSnapshot { count: 1 << 33, sum: Duration::from_secs(3 << 33), buckets: vec!{} };
+        assert_eq!(many.mean(), Duration::from_secs(3));
+        assert_eq!(Histogram::new().snapshot().mean(), Duration::ZERO);
+    }
+
+    #[test]
+    fn errors_by_kind() {
+        let metrics = Metrics::new(2);
+        metrics.error(Failure::Deserialize);
+        metrics.error(Failure::Deserialize);
+        metrics.error(Failure::StaleResponse);
+
+        let stats = metrics.stats();
+        assert_eq!(stats.errors("deserialize"), 2);
+        assert_eq!(stats.errors("stale_response"), 1);
+        assert_eq!(stats.errors("read"), 0);
+        assert_eq!(stats.worker_queue_depths, vec![0, 0]);
+    }
+}
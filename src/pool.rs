@@ -0,0 +1,58 @@
+use bytes::BytesMut;
+
+// Read buffers kept by an I/O thread between uses. A connection takes one when data arrives
+// and gives it back once every frame has been split off it, so the number of buffers follows
+// the number of connections with a partial frame rather than the number of connections.
+pub struct BufferPool {
+    free: Vec<BytesMut>,
+    buf_size: usize,
+    max_free: usize,
+}
+
+impl BufferPool {
+    pub fn new(buf_size: usize, max_free: usize) -> BufferPool {
+        BufferPool {
+            free: Vec::with_capacity(max_free),
+            buf_size,
+            max_free,
+        }
+    }
+
+    pub fn take(&mut self) -> BytesMut {
+        match self.free.pop() {
+            Some(buf) => buf,
+            None => BytesMut::with_capacity(self.buf_size),
+        }
+    }
+
+    // Frames split off the buffer may still be alive on a worker. The allocation is only
+    // reclaimed once they are dropped, until then reading into it allocates a fresh one.
+    pub fn give(&mut self, mut buf: BytesMut) {
+        if self.free.len() < self.max_free {
+            buf.clear();
+            self.free.push(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferPool;
+
+    #[test]
+    fn buffers_are_reused() {
+        let mut pool = BufferPool::new(1024, 1);
+        let first = pool.take();
+        let second = pool.take();
+        let first_ptr = first.as_ptr();
+
+        pool.give(first);
+        // over max_free, dropped rather than kept
+        pool.give(second);
+
+        let buf = pool.take();
+        assert_eq!(buf.as_ptr(), first_ptr);
+        assert!(buf.capacity() >= 1024);
+        assert!(pool.free.is_empty());
+    }
+}
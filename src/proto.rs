@@ -0,0 +1,119 @@
+// Protocol Buffers support behind the `prost` feature.
+
+use bytes::Bytes;
+use prost::{Message, Name};
+
+use service::Codec;
+use errors::*;
+
+const CODEC: &str = "protobuf";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProstCodec;
+
+impl<Req: Message + Default, Resp: Message> Codec<Req, Resp> for ProstCodec {
+    fn decode(&self, buf: Bytes) -> Result<Req> {
+        Req::decode(buf).map_err(|e| ErrorKind::Codec(CODEC, e.to_string()).into())
+    }
+
+    fn encode(&self, msg: Resp) -> Result<Bytes> {
+        Ok(msg.encode_to_vec().into())
+    }
+}
+
+// Wraps a message together with the name of its type, so a service taking Envelope requests
+// through ProstCodec can accept several message types on one port. Laid out like
+// google.protobuf.Any, so clients can send an Any and `type_name` may be a full type url.
+#[derive(Clone, PartialEq, Message)]
+pub struct Envelope {
+    #[prost(string, tag = "1")]
+    pub type_name: String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub payload: Bytes,
+}
+
+impl Envelope {
+    pub fn pack<M: Name>(msg: &M) -> Envelope {
+        Envelope {
+            type_name: M::full_name(),
+            payload: msg.encode_to_vec().into(),
+        }
+    }
+
+    // Compares against the message's full name, ignoring any url prefix on type_name.
+    pub fn is<M: Name>(&self) -> bool {
+        let name = self.type_name.rsplit('/').next().unwrap_or("");
+        M::full_name() == name
+    }
+
+    pub fn unpack<M: Name + Default>(&self) -> Result<M> {
+        if !self.is::<M>() {
+            let reason = format!("envelope holds {}, not {}", self.type_name, M::full_name());
+            return Err(ErrorKind::Codec(CODEC, reason).into());
+        }
+        M::decode(self.payload.clone()).map_err(|e| ErrorKind::Codec(CODEC, e.to_string()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use prost::{Message, Name};
+    use service::Codec;
+    use errors::*;
+    use super::{Envelope, ProstCodec};
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Ping {
+        #[prost(uint64, tag = "1")]
+        seq: u64,
+    }
+
+    impl Name for Ping {
+        const NAME: &'static str = "Ping";
+        const PACKAGE: &'static str = "test";
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Echo {
+        #[prost(string, tag = "1")]
+        text: String,
+    }
+
+    impl Name for Echo {
+        const NAME: &'static str = "Echo";
+        const PACKAGE: &'static str = "test";
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let buf = Codec::<Echo, Ping>::encode(&ProstCodec, Ping { seq: 150 }).expect("couldn't encode");
+        assert_eq!(buf, Bytes::from_static(&[0x08, 0x96, 0x01]));
+        let ping: Ping = Codec::<Ping, Echo>::decode(&ProstCodec, buf).expect("couldn't decode");
+        assert_eq!(ping.seq, 150);
+
+        match Codec::<Ping, Echo>::decode(&ProstCodec, Bytes::from_static(&[0x08, 0x96])) {
+            Err(Error(ErrorKind::Codec("protobuf", _), _)) => {},
+            other => panic!("expected a codec error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn envelopes_carry_the_type() {
+        let buf = Codec::<Envelope, Envelope>::encode(&ProstCodec, Envelope::pack(&Echo { text: "hi".to_owned() }))
+            .expect("couldn't encode");
+        let envelope: Envelope = Codec::<Envelope, Envelope>::decode(&ProstCodec, buf).expect("couldn't decode");
+
+        assert_eq!(envelope.type_name, "test.Echo");
+        assert!(envelope.is::<Echo>());
+        assert!(!envelope.is::<Ping>());
+        assert_eq!(envelope.unpack::<Echo>().expect("couldn't unpack").text, "hi");
+        assert!(envelope.unpack::<Ping>().is_err());
+
+        let any = Envelope {
+            type_name: "type.googleapis.com/test.Ping".to_owned(),
+            payload: Ping { seq: 3 }.encode_to_vec().into(),
+        };
+        assert_eq!(any.unpack::<Ping>().expect("couldn't unpack").seq, 3);
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::{HashMap, HashSet};
+use bytes::Bytes;
+
+use worker::{MessageSink, Outbound};
+use connection::ConnId;
+use stream::{self, FLAG_PUSH, PUSH_ID};
+use errors::*;
+
+#[derive(Debug, Clone)]
+pub struct Publisher {
+    sink: MessageSink,
+    correlation: bool,
+}
+
+impl Publisher {
+    pub fn new(sink: MessageSink, correlation: bool) -> Publisher {
+        Publisher {
+            sink,
+            correlation,
+        }
+    }
+
+    pub fn broadcast<B: Into<Bytes>>(&self, buf: B) -> Result<()> {
+        self.sink.send(Outbound::Broadcast(self.frame(buf.into())))
+    }
+
+    pub fn publish<B: Into<Bytes>>(&self, topic: &str, buf: B) -> Result<()> {
+        self.sink.send(Outbound::Publish(topic.to_owned(), self.frame(buf.into())))
+    }
+
+    pub fn subscribe(&self, conn: ConnId, topic: &str) -> Result<()> {
+        self.sink.send(Outbound::Subscribe(conn, topic.to_owned()))
+    }
+
+    pub fn unsubscribe(&self, conn: ConnId, topic: &str) -> Result<()> {
+        self.sink.send(Outbound::Unsubscribe(conn, topic.to_owned()))
+    }
+
+    // Correlating clients tell pushed messages from replies by their flag.
+    fn frame(&self, buf: Bytes) -> Bytes {
+        if self.correlation {
+            stream::frame_response(PUSH_ID, FLAG_PUSH, &buf)
+        } else {
+            buf
+        }
+    }
+}
+
+// Lives on the I/O thread alongside the connection slab. The reverse index lets a closing
+// connection drop its subscriptions without scanning every topic.
+#[derive(Default)]
+pub struct Topics {
+    subscribers: HashMap<String, HashSet<ConnId>>,
+    subscriptions: HashMap<ConnId, HashSet<String>>,
+}
+
+impl Topics {
+    pub fn new() -> Topics {
+        Topics::default()
+    }
+
+    pub fn subscribe(&mut self, conn: ConnId, topic: String) {
+        self.subscriptions.entry(conn).or_default().insert(topic.clone());
+        self.subscribers.entry(topic).or_default().insert(conn);
+    }
+
+    pub fn unsubscribe(&mut self, conn: ConnId, topic: &str) {
+        if let Some(topics) = self.subscriptions.get_mut(&conn) {
+            topics.remove(topic);
+            if topics.is_empty() {
+                self.subscriptions.remove(&conn);
+            }
+        }
+        self.remove_subscriber(conn, topic);
+    }
+
+    pub fn subscribers(&self, topic: &str) -> Vec<ConnId> {
+        match self.subscribers.get(topic) {
+            Some(conns) => conns.iter().cloned().collect(),
+            None => vec!{},
+        }
+    }
+
+    pub fn remove_conn(&mut self, conn: ConnId) {
+        if let Some(topics) = self.subscriptions.remove(&conn) {
+            for topic in topics {
+                self.remove_subscriber(conn, &topic);
+            }
+        }
+    }
+
+    fn remove_subscriber(&mut self, conn: ConnId, topic: &str) {
+        let empty = match self.subscribers.get_mut(topic) {
+            Some(conns) => {
+                conns.remove(&conn);
+                conns.is_empty()
+            },
+            None => false,
+        };
+
+        if empty {
+            self.subscribers.remove(topic);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Topics;
+    use connection::ConnId;
+
+    #[test]
+    fn subscribe_and_remove_conn() {
+        let first = ConnId::new(0, 1, 0);
+        let second = ConnId::new(0, 2, 1);
+        let mut topics = Topics::new();
+        topics.subscribe(first, "news".to_owned());
+        topics.subscribe(second, "news".to_owned());
+        topics.subscribe(first, "sports".to_owned());
+
+        let mut subs = topics.subscribers("news");
+        subs.sort_by_key(|conn| conn.idx());
+        assert_eq!(subs, vec![first, second]);
+
+        topics.unsubscribe(second, "news");
+        assert_eq!(topics.subscribers("news"), vec![first]);
+
+        topics.remove_conn(first);
+        assert!(topics.subscribers("news").is_empty());
+        assert!(topics.subscribers("sports").is_empty());
+    }
+}
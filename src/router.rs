@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use byteorder::{ByteOrder, BigEndian};
+use bytes::Bytes;
+
+use ::MessageHandler;
+use handler::{Dispatch, Unary, Streaming};
+use async_handler::{AsyncMessageHandler, Async};
+use stream::{StreamingMessageHandler, Responses};
+use worker::{self, Context};
+use metrics::Failure;
+use errors::*;
+
+pub const TAG_LEN: usize = 2;
+
+// Serves several handlers on one port. Every request starts with a big endian u16 type tag,
+// after the correlation id when that is enabled, and the rest of the frame goes to the
+// handler registered for that tag. Responses are not tagged.
+#[derive(Default)]
+pub struct Router {
+    routes: HashMap<u16, Box<dyn Dispatch>>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: HashMap::new(),
+        }
+    }
+
+    // Takes a &'static handler or an Arc of one.
+    pub fn route<H: MessageHandler + Send + 'static>(self, tag: u16, handler: H) -> Router {
+        self.add(tag, Box::new(Unary::new(handler)))
+    }
+
+    pub fn route_streaming<H: StreamingMessageHandler + Send + 'static>(self, tag: u16, handler: H) -> Router {
+        self.add(tag, Box::new(Streaming::new(handler)))
+    }
+
+    pub fn route_async<H>(self, tag: u16, handler: H) -> Router
+            where H: AsyncMessageHandler + Send + 'static, H::Resp: 'static {
+        self.add(tag, Box::new(Async::new(handler)))
+    }
+
+    fn add(mut self, tag: u16, route: Box<dyn Dispatch>) -> Router {
+        assert!(self.routes.insert(tag, route).is_none(), "type tag {} is already routed", tag);
+        self
+    }
+}
+
+impl Dispatch for Router {
+    fn dispatch(&self, buf: Bytes, ctx: &Context, responses: &mut Responses) -> Result<()> {
+        if buf.len() < TAG_LEN {
+            worker::failed(ctx, Failure::Deserialize);
+            return Err("message too short to carry a type tag".into());
+        }
+
+        let tag = BigEndian::read_u16(&buf[..TAG_LEN]);
+        match self.routes.get(&tag) {
+            Some(route) => route.dispatch(buf.slice(TAG_LEN..), ctx, responses),
+            None => {
+                worker::failed(ctx, Failure::Deserialize);
+                Err(format!("no handler for type tag {}", tag).into())
+            }
+        }
+    }
+}
@@ -1,42 +1,203 @@
-use mio::{Poll, Events, Token, PollOpt, Ready};
+use mio::{Poll, Events, Token, PollOpt, Ready, Registration, SetReadiness};
 use mio::net::{TcpListener, TcpStream};
 use mio::unix::UnixReady;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::mem;
+use std::time::Instant;
 use std::sync::mpsc::Sender; 
+use std::task::{self, Waker};
+use crossbeam_queue::SegQueue;
 use slab::Slab;
-use connection::Connection;
-use worker::{MsgBuf, MessageSource};
+use net2::TcpBuilder;
+use net2::unix::UnixTcpBuilderExt;
+use std::net::{IpAddr, SocketAddr};
+use connection::{Connection, ConnId, ReadStatus};
+use worker::{MsgBuf, Request, WorkerMsg, MessageSource, Outbound, Processor, Progress, Task};
+use stream::Outlet;
+use executor;
+use pubsub::Topics;
+use pool::BufferPool;
+use metrics::{Metrics, Failure};
 use errors::*;
 
 use std::io::ErrorKind;
+use bytes::Bytes;
+
+// Tokens below RESERVED_TOKENS belong to the server itself, connection tokens are the slab
+// key offset past them so no number of connections can alias a control token.
+const LISTENER: Token = Token(0);
+const WRITE_PIPELINE: Token = Token(1);
+const READ_BACKLOG: Token = Token(2);
+const TASK_WAKEUPS: Token = Token(3);
+const RESERVED_TOKENS: usize = 4;
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+const POOLED_READ_BUFFERS: usize = 128;
+
+fn conn_token(conn_idx: usize) -> Token {
+    Token(conn_idx + RESERVED_TOKENS)
+}
+
+fn token_conn(token: Token) -> Option<usize> {
+    usize::from(token).checked_sub(RESERVED_TOKENS)
+}
+
+// With more than one I/O thread every thread gets its own listener bound with SO_REUSEPORT
+// and the kernel spreads incoming connections across them.
+pub fn bind_listeners(addr: &SocketAddr, count: usize) -> Result<Vec<TcpListener>> {
+    if count == 1 {
+        return Ok(vec![TcpListener::bind(addr)?]);
+    }
+
+    let first = bind_reuseport(addr)?;
+    // resolve the port once so an ephemeral port is shared by all listeners
+    let addr = first.local_addr()?;
+    let mut socks = vec![first];
+
+    for _ in 1..count {
+        socks.push(bind_reuseport(&addr)?);
+    }
+
+    Ok(socks)
+}
+
+fn bind_reuseport(addr: &SocketAddr) -> Result<TcpListener> {
+    let builder = match *addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    builder.reuse_address(true)?;
+    builder.reuse_port(true)?;
+    builder.bind(addr)?;
+    let sock = builder.listen(1024)?;
+    Ok(TcpListener::from_std(sock)?)
+}
 
 pub struct Server {
+    io: usize,
     conns: Slab<Connection>,
     sock: TcpListener,
-    token: Token,
-    write_token: Token,
     events: Events,
-    read: Vec<Sender<MsgBuf>>,
+    read: Vec<Sender<WorkerMsg>>,
     write: MessageSource,
     read_idx: usize,
+    inline: Option<Processor>,
+    read_budget: usize,
+    read_buffers: BufferPool,
+    backlog: Vec<ConnId>,
+    backlog_registration: Registration,
+    backlog_readiness: SetReadiness,
+    // requests waiting on async handlers when running to completion, polled again once the
+    // loop's wakeup registration turns readable
+    tasks: Slab<(Box<Task>, Waker)>,
+    woken: Arc<SegQueue<usize>>,
+    task_registration: Registration,
+    task_readiness: SetReadiness,
+    topics: Topics,
+    pending_flush: HashSet<ConnId>,
+    next_gen: u64,
+    metrics: Arc<Metrics>,
+}
+
+// Flushes a connection, timing the write and counting the bytes that went out.
+fn flush(conn: &mut Connection, metrics: &Metrics) -> Result<()> {
+    let written = conn.bytes_written();
+    let start = Instant::now();
+    let res = conn.flush();
+    metrics.write.record(start.elapsed());
+    metrics.bytes_out.add(conn.bytes_written() - written);
+    res
+}
+
+// Writes a connection's queued messages out and updates its interest to match.
+fn write_out(conn: &mut Connection, metrics: &Metrics, poll: &mut Poll) -> Result<()> {
+    flush(conn, metrics)?;
+    conn.register(poll, false)?;
+    Ok(())
+}
+
+// Where responses go when running to completion. They are queued on their connection right
+// away, and a streaming handler's responses are written out as it sends them instead of once
+// the batch is done. Connections that fail are left for the server to remove.
+struct Inline<'a> {
+    conns: &'a mut Slab<Connection>,
+    pending_flush: &'a mut HashSet<ConnId>,
+    failed: &'a mut Vec<ConnId>,
+    metrics: &'a Metrics,
+    poll: &'a mut Poll,
+}
+
+impl<'a> Inline<'a> {
+    fn conn(&mut self, id: ConnId) -> Result<&mut Connection> {
+        match self.conns.get_mut(id.idx()) {
+            Some(conn) if conn.id == id && !self.failed.contains(&id) => Ok(conn),
+            _ => Err(format!("connection {:?} is closed", id).into()),
+        }
+    }
+}
+
+impl<'a> Outlet for Inline<'a> {
+    fn send_message(&mut self, msg: MsgBuf) -> Result<()> {
+        self.conn(msg.conn)?.send_message(msg.buf)?;
+        self.metrics.frames_out.inc();
+        self.pending_flush.insert(msg.conn);
+        Ok(())
+    }
+
+    fn flush(&mut self, id: ConnId) -> Result<()> {
+        let (metrics, poll) = (self.metrics, &mut *self.poll);
+        let res = match self.conns.get_mut(id.idx()) {
+            Some(conn) if conn.id == id => write_out(conn, metrics, poll),
+            _ => return Ok(()),
+        };
+        self.pending_flush.remove(&id);
+
+        if let Err(e) = res {
+            self.metrics.error(Failure::Write);
+            error!("failed to flush connection {:?} due to error {:?}", id, e);
+            self.failed.push(id);
+            return Err(e);
+        }
+        Ok(())
+    }
 }
 
 impl Server {
-    pub fn new(sock: TcpListener, read: Vec<Sender<MsgBuf>>, write: MessageSource) -> Server {
+    pub fn new(io: usize, sock: TcpListener, read: Vec<Sender<WorkerMsg>>, write: MessageSource,
+            inline: Option<Processor>, read_budget: usize, metrics: Arc<Metrics>) -> Server {
+        let (backlog_registration, backlog_readiness) = Registration::new2();
+        let (task_registration, task_readiness) = Registration::new2();
         Server {
+            io,
             conns: Slab::with_capacity(128),
-            sock: sock,
-            token: Token(10_000_000),
-            write_token: Token(10_000_001),
+            sock,
             events: Events::with_capacity(1024),
-            read: read,
-            write: write,
+            read,
+            write,
             read_idx: 0,
+            inline,
+            read_budget,
+            read_buffers: BufferPool::new(READ_BUFFER_SIZE, POOLED_READ_BUFFERS),
+            backlog: vec!{},
+            backlog_registration,
+            backlog_readiness,
+            tasks: Slab::new(),
+            woken: Arc::new(SegQueue::new()),
+            task_registration,
+            task_readiness,
+            topics: Topics::new(),
+            pending_flush: HashSet::new(),
+            next_gen: 0,
+            metrics,
         }
     }
 
     pub fn run(&mut self, poll: &mut Poll) -> Result<()> {
-        poll.register(&self.sock, self.token, Ready::readable(), PollOpt::edge())?;
-        poll.register(&self.write, self.write_token, Ready::writable(), PollOpt::edge())?;
+        poll.register(&self.sock, LISTENER, Ready::readable(), PollOpt::edge())?;
+        poll.register(&self.write, WRITE_PIPELINE, Ready::writable(), PollOpt::edge())?;
+        poll.register(&self.backlog_registration, READ_BACKLOG, Ready::readable(), PollOpt::edge())?;
+        poll.register(&self.task_registration, TASK_WAKEUPS, Ready::readable(), PollOpt::edge())?;
         
         loop {
             let cnt = poll.poll(&mut self.events, None)?;
@@ -48,6 +209,9 @@ impl Server {
                     match self.handle_event(evt.token(), evt.readiness(), poll) {
                         Ok(true) => {},
                         Ok(false) => {
+                            if !self.tasks.is_empty() {
+                                warn!("server dropping {} unfinished requests", self.tasks.len());
+                            }
                             info!("exiting server loop");
                             return Ok(());
                         }
@@ -60,54 +224,162 @@ impl Server {
         }
     }
 
-    fn handle_writes(&mut self) {
-        let mut new_writes = Vec::new();
+    fn handle_writes(&mut self, poll: &mut Poll) -> bool {
+        for out in self.write.drain() {
+            match out {
+                Outbound::Reply(msg) => {
+                    self.deliver(msg.conn, msg.buf);
+                },
+                Outbound::Broadcast(buf) => {
+                    let conns: Vec<ConnId> = self.conns.iter().map(|(_, conn)| conn.id).collect();
+                    debug!("broadcasting message to {} connections", conns.len());
+                    for conn in conns {
+                        self.deliver(conn, buf.clone());
+                    }
+                },
+                Outbound::Publish(topic, buf) => {
+                    let conns = self.topics.subscribers(&topic);
+                    debug!("publishing message on topic {} to {} connections", topic, conns.len());
+                    for conn in conns {
+                        self.deliver(conn, buf.clone());
+                    }
+                },
+                Outbound::Subscribe(conn, topic) => {
+                    if self.lookup_id(conn).is_some() {
+                        self.topics.subscribe(conn, topic);
+                    } else {
+                        info!("ignoring subscription to {} for closed connection {:?}", topic, conn);
+                    }
+                },
+                Outbound::Unsubscribe(conn, topic) => {
+                    self.topics.unsubscribe(conn, &topic);
+                },
+                Outbound::ListConnections(reply) => {
+                    let _ = reply.send(self.conns.iter().map(|(_, conn)| conn.snapshot()).collect());
+                },
+                Outbound::Close(id, reply) => {
+                    let _ = reply.send(self.close_conn(id));
+                },
+                Outbound::ClosePeer(ip, reply) => {
+                    let _ = reply.send(self.close_peer(ip));
+                },
+                Outbound::Shutdown => {
+                    info!("server received shutdown");
+                    return false;
+                },
+            }
+        }
 
-        for msg in self.write.try_iter() {
-            new_writes.push(msg);
+        self.flush_pending(poll);
+        true
+    }
+
+    fn deliver(&mut self, id: ConnId, buf: Bytes) {
+        let res = match self.lookup_id(id) {
+            Some(conn) => conn.send_message(buf),
+            None => return,
+        };
+
+        match res {
+            Ok(()) => {
+                self.metrics.frames_out.inc();
+                self.pending_flush.insert(id);
+            },
+            Err(e) => {
+                self.metrics.error(Failure::Write);
+                error!("failed to send message to connection {:?}", e);
+                self.remove_conn(id.idx());
+            }
         }
+    }
 
-        for msg in new_writes {
-            if let Some(conn) = self.lookup_conn(msg.conn_idx) {
-                match conn.send_message(msg.buf) {
-                    Ok(_) => {},
-                    Err(e) => {
-                        error!("failed to send message to connection {:?}", e);
-                    }
+    // Writes out everything queued by deliver, one writev per connection however many
+    // messages it was sent.
+    fn flush_pending(&mut self, poll: &mut Poll) {
+        let pending: Vec<ConnId> = self.pending_flush.drain().collect();
+
+        for id in pending {
+            let mut remove = false;
+
+            if let Some(conn) = self.conns.get_mut(id.idx()) {
+                if conn.id != id {
+                    continue;
+                }
+
+                if let Err(e) = write_out(conn, &self.metrics, poll) {
+                    self.metrics.error(Failure::Write);
+                    error!("failed to flush connection {:?} due to error {:?}", id, e);
+                    remove = true;
                 }
             }
+
+            if remove {
+                self.remove_conn(id.idx());
+            }
         }
     }
 
-    fn handle_event(&mut self, token: Token, event: Ready, poll: &mut Poll) -> Result<(bool)> {
+    fn handle_event(&mut self, token: Token, event: Ready, poll: &mut Poll) -> Result<bool> {
         debug!("{:?} event = {:?}", token, event);
-        if token == self.write_token {
-            self.handle_writes();
-            return Ok(true);
+        if token == WRITE_PIPELINE {
+            return Ok(self.handle_writes(poll));
         }
 
-        let conn_idx = usize::from(token);
+        if token == READ_BACKLOG {
+            return Ok(self.resume_reads(poll));
+        }
 
-        if self.token != token && !self.conns.contains(conn_idx) {
-            warn!("unable to find connection for token {:?}", token);
+        if token == TASK_WAKEUPS {
+            self.poll_tasks(poll);
             return Ok(true);
         }
 
+        if token == LISTENER {
+            assert!(!event.is_writable(), "received writable event for server");
+            if event.is_readable() {
+                self.accept(poll);
+            }
+            return Ok(true);
+        }
+
+        let conn_idx = match token_conn(token) {
+            Some(conn_idx) if self.conns.contains(conn_idx) => conn_idx,
+            _ => {
+                warn!("unable to find connection for token {:?}", token);
+                return Ok(true);
+            }
+        };
+
         let uevent = UnixReady::from(event);
-        if uevent.is_error()|| uevent.is_hup() {
+        if uevent.is_error() {
             warn!("error signaled for connection {:?}", token);
             self.remove_conn(conn_idx);
             return Ok(true);
         }
 
+        if uevent.is_hup() {
+            // the peer may have written its last requests right before hanging up, though only
+            // those run to completion get handled before the connection goes
+            let mut keep_running = true;
+            if event.is_readable() {
+                match self.dispatch_messages(conn_idx, true, poll) {
+                    Ok(running) => keep_running = running,
+                    Err(e) => warn!("failed to dispatch messages for connection {:?} due to error {:?}", token, e),
+                }
+            }
+            info!("connection {:?} hung up", token);
+            self.remove_conn(conn_idx);
+            return Ok(keep_running);
+        }
+
         if event.is_writable() {
-            assert!(self.token != token, "received writable event for server");
             let mut write_fail = false;
 
-            if let Some(conn) = self.lookup_conn(conn_idx) {
-                match conn.handle_write() {
+            if let Some(conn) = self.conns.get_mut(conn_idx) {
+                match flush(conn, &self.metrics) {
                     Ok(()) => {},
                     Err(e) => {
+                        self.metrics.error(Failure::Write);
                         warn!("write event failed for connection {:?} due to error {:?}", token, e);
                         write_fail = true;
                     }
@@ -115,40 +387,34 @@ impl Server {
             } 
 
             if write_fail {
-                self.conns.remove(conn_idx);
+                self.remove_conn(conn_idx);
                 return Ok(true);
             } 
         } 
         
         if event.is_readable() {
-            if self.token == token {
-                self.accept(poll);
-            } else {
-                match self.dispatch_messages(conn_idx) {
-                    Ok(true) => {},
-                    Ok(false) => return Ok(false),
-                    Err(e) => {
-                        warn!("failed to dispatch messages for connection {:?} due to error {:?}", token, e);
-                    }
-                }    
-            }
+            match self.dispatch_messages(conn_idx, false, poll) {
+                Ok(true) => {},
+                Ok(false) => return Ok(false),
+                Err(e) => {
+                    warn!("failed to dispatch messages for connection {:?} due to error {:?}", token, e);
+                }
+            }    
         }
 
-        if self.token != token {
-            let mut remove = false;
-            if let Some(conn) = self.lookup_conn(conn_idx) {
-                match conn.register(poll, false) {
-                    Ok(()) => {},
-                    Err(e) => {
-                        warn!("unable to reregister connection {:?} due to error {:?}", token, e);
-                        remove = true;
-                    }
+        let mut remove = false;
+        if let Some(conn) = self.lookup_conn(conn_idx) {
+            match conn.register(poll, false) {
+                Ok(_) => {},
+                Err(e) => {
+                    warn!("unable to reregister connection {:?} due to error {:?}", token, e);
+                    remove = true;
                 }
             }
+        }
 
-            if remove {
-                self.remove_conn(conn_idx);
-            }
+        if remove {
+            self.remove_conn(conn_idx);
         }
 
         Ok(true)
@@ -160,14 +426,23 @@ impl Server {
                 Ok((sock, _)) => sock,
                 Err(e) => {
                     if e.kind() != ErrorKind::WouldBlock {
+                        self.metrics.error(Failure::Accept);
                         error!("failed to accept new socket, {:?}", e);
                     }
                     return;
                 }
             };
 
-            let conn_idx = self.add_conn(sock);
-            let token = Token::from(conn_idx);
+            // writes are already coalesced by the connection, Nagle would only hold back the
+            // tail of a batch waiting on a delayed ack
+            if let Err(e) = sock.set_nodelay(true) {
+                warn!("unable to disable Nagle's algorithm on new socket, {:?}", e);
+            }
+
+            self.metrics.connections_accepted.inc();
+            self.metrics.active_connections.inc();
+            let conn_idx = self.add_conn(sock).idx();
+            let token = conn_token(conn_idx);
 
             debug!("registering {:?} with poller", token);
             let mut remove = false;
@@ -188,40 +463,263 @@ impl Server {
         }
     }
 
-    fn dispatch_messages(&mut self, conn_idx: usize) -> Result<(bool)> {
-        let read_idx = self.read_idx;
-        self.read_idx = (read_idx+1) % self.read.len();
-        
-        let mut new_msgs = Vec::new();
+    fn dispatch_messages(&mut self, conn_idx: usize, hung_up: bool, poll: &mut Poll) -> Result<bool> {
+        let budget = self.read_budget;
+        let mut bufs = Vec::new();
 
-        if let Some(conn) = self.lookup_conn(conn_idx) {
-            while let Some(message) = conn.handle_read()? {
-                new_msgs.push(message);   
+        let (id, info, span, status) = match self.conns.get_mut(conn_idx) {
+            Some(conn) => {
+                let read = conn.bytes_read();
+                let res = conn.read_messages(&mut self.read_buffers, budget, &mut bufs);
+                self.metrics.bytes_in.add(conn.bytes_read() - read);
+
+                match res {
+                    Ok(status) => (conn.id, conn.info.clone(), conn.span.clone(), status),
+                    Err(e) => {
+                        self.metrics.error(Failure::Read);
+                        return Err(e);
+                    }
+                }
+            },
+            None => {
+                info!("unable to look up connection {}", conn_idx);
+                return Ok(true);
             }
+        };
+
+        match status {
+            ReadStatus::Drained => {},
+            ReadStatus::Budget => self.defer_read(id),
+            ReadStatus::Closed => info!("connection {:?} closed by peer", id),
+        }
+
+        self.metrics.frames_in.add(bufs.len() as u64);
+        // the connection closes before a worker could pick these up, and workers skip requests
+        // from closed connections, so they aren't sent on only to be counted as cancelled
+        let gone = hung_up || status == ReadStatus::Closed;
+        if gone && self.inline.is_none() {
+            if !bufs.is_empty() {
+                info!("dropping {} requests from connection {:?}, which hung up", bufs.len(), id);
+            }
+            self.remove_conn(conn_idx);
+            return Ok(true);
+        }
+
+        let enqueued = Instant::now();
+        let new_msgs: Vec<Request> = bufs.into_iter()
+            .map(|buf| Request::new(MsgBuf::new(id, buf), enqueued, span.request(), info.clone()))
+            .collect();
+        let running = self.send_to_handler(conn_idx, new_msgs, poll);
+
+        if status == ReadStatus::Closed {
+            self.remove_conn(conn_idx);
+        }
+
+        Ok(running)
+    }
+
+    fn send_to_handler(&mut self, conn_idx: usize, new_msgs: Vec<Request>, poll: &mut Poll) -> bool {
+        if self.inline.is_some() {
+            self.process_inline(new_msgs, poll);
+            return true;
+        }
+
+        let read_idx = self.read_idx;
+        self.read_idx = (read_idx+1) % self.read.len();
+        if let Some(conn) = self.conns.get_mut(conn_idx) {
+            conn.worker = Some(read_idx);
         }
 
         for message in new_msgs {
-            match self.read[read_idx].send(MsgBuf::new(conn_idx, message)) {
-                Ok(()) => {},
+            match self.read[read_idx].send(WorkerMsg::Request(message)) {
+                Ok(()) => self.metrics.worker_queues[read_idx].inc(),
                 Err(e) => {
+                    self.metrics.error(Failure::Dispatch);
                     info!("unable to dispatch message for connection {} due to {:?}. presuming shutdown", conn_idx, e);
-                    return Ok(false);
+                    return false;
                 }
             }
         }
 
-        Ok(true)
+        true
+    }
+
+    // A connection that used up its read budget goes on the backlog and the loop wakes itself
+    // up to continue with it, after the other connections had a turn. Edge-triggered polling
+    // won't report the unread data again on its own.
+    fn defer_read(&mut self, id: ConnId) {
+        match self.conns.get_mut(id.idx()) {
+            Some(ref mut conn) if conn.id == id && !conn.deferred => {
+                conn.deferred = true;
+                self.backlog.push(id);
+            },
+            _ => {},
+        }
+
+        if let Err(e) = self.backlog_readiness.set_readiness(Ready::readable()) {
+            error!("unable to schedule deferred read for connection {:?}, {:?}", id, e);
+        }
+    }
+
+    fn resume_reads(&mut self, poll: &mut Poll) -> bool {
+        if let Err(e) = self.backlog_readiness.set_readiness(Ready::empty()) {
+            warn!("unable to reset read backlog readiness, {:?}", e);
+        }
+
+        let backlog = mem::take(&mut self.backlog);
+        debug!("resuming reads for {} connections", backlog.len());
+
+        for id in backlog {
+            match self.conns.get_mut(id.idx()) {
+                Some(ref mut conn) if conn.id == id => conn.deferred = false,
+                _ => continue,
+            }
+
+            match self.dispatch_messages(id.idx(), false, poll) {
+                Ok(true) => {},
+                Ok(false) => return false,
+                Err(e) => {
+                    warn!("failed to dispatch messages for connection {:?} due to error {:?}", id, e);
+                }
+            }
+        }
+
+        true
     }
 
-    fn add_conn(&mut self, sock: TcpStream) -> usize {
+    // Run to completion: the handler runs right here on the I/O thread and its responses are
+    // queued on the connection without a round trip through a worker. Async handlers leave a
+    // task behind that the loop polls whenever it is woken.
+    fn process_inline(&mut self, reqs: Vec<Request>, poll: &mut Poll) {
+        let mut spawned = Vec::new();
+        let mut failed = Vec::new();
+
+        if let Some(ref processor) = self.inline {
+            let mut out = Inline {
+                conns: &mut self.conns,
+                pending_flush: &mut self.pending_flush,
+                failed: &mut failed,
+                metrics: &self.metrics,
+                poll: &mut *poll,
+            };
+            for req in reqs {
+                if let Progress::Pending(task) = processor.start(req, &mut out) {
+                    let entry = self.tasks.vacant_entry();
+                    spawned.push(entry.key());
+                    let waker = executor::loop_waker(entry.key(), self.woken.clone(), self.task_readiness.clone());
+                    entry.insert((task, waker));
+                }
+            }
+        }
+
+        for id in failed {
+            self.remove_conn(id.idx());
+        }
+
+        let mut replies = Vec::new();
+        for key in spawned {
+            self.poll_task(key, &mut replies);
+        }
+        self.send_replies(replies, poll);
+    }
+
+    fn poll_tasks(&mut self, poll: &mut Poll) {
+        if let Err(e) = self.task_readiness.set_readiness(Ready::empty()) {
+            warn!("unable to reset task wakeup readiness, {:?}", e);
+        }
+
+        let mut replies = Vec::new();
+        while let Some(key) = self.woken.pop() {
+            self.poll_task(key, &mut replies);
+        }
+        self.send_replies(replies, poll);
+    }
+
+    // Wakeups can outlive their task, and a slot may hold a newer task by then, which is
+    // polled for nothing.
+    fn poll_task(&mut self, key: usize, replies: &mut Vec<MsgBuf>) {
+        let processor = match self.inline {
+            Some(ref processor) => processor,
+            None => return,
+        };
+
+        let result = match self.tasks.get_mut(key) {
+            Some(&mut (ref mut task, ref waker)) => {
+                match processor.poll(task, &mut task::Context::from_waker(waker)) {
+                    task::Poll::Ready(result) => result,
+                    task::Poll::Pending => return,
+                }
+            },
+            None => return,
+        };
+
+        let (task, _) = self.tasks.remove(key);
+        processor.complete(*task, result, replies);
+    }
+
+    fn send_replies(&mut self, replies: Vec<MsgBuf>, poll: &mut Poll) {
+        for reply in replies {
+            self.deliver(reply.conn, reply.buf);
+        }
+        self.flush_pending(poll);
+    }
+
+    fn close_conn(&mut self, id: ConnId) -> bool {
+        match self.conns.get(id.idx()) {
+            Some(conn) if conn.id == id => {},
+            _ => return false,
+        }
+
+        info!("closing connection {} on request", id);
+        self.remove_conn(id.idx());
+        true
+    }
+
+    fn close_peer(&mut self, ip: IpAddr) -> usize {
+        let idxs: Vec<usize> = self.conns.iter()
+            .filter(|&(_, conn)| conn.info.peer.map(|peer| peer.ip()) == Some(ip))
+            .map(|(idx, _)| idx)
+            .collect();
+
+        info!("closing {} connections from {} on request", idxs.len(), ip);
+        for idx in &idxs {
+            self.remove_conn(*idx);
+        }
+        idxs.len()
+    }
+
+    fn add_conn(&mut self, sock: TcpStream) -> ConnId {
         let entry = self.conns.vacant_entry();
-        let conn_idx = entry.key();
-        entry.insert(Connection::new(sock, Token::from(conn_idx)));
-        conn_idx
+        let id = ConnId::new(self.io, entry.key(), self.next_gen);
+        self.next_gen += 1;
+        entry.insert(Connection::new(sock, id, conn_token(id.idx())));
+        id
     }
 
     fn remove_conn(&mut self, conn_idx: usize) {
-       self.conns.remove(conn_idx);
+        if self.conns.contains(conn_idx) {
+            let conn = self.conns.remove(conn_idx);
+            conn.info.close();
+            conn.span.closed(conn.bytes_read(), conn.bytes_written());
+            self.topics.remove_conn(conn.id);
+            self.metrics.connections_closed.inc();
+            self.metrics.active_connections.dec();
+        }
+    }
+
+    fn lookup_id(&mut self, id: ConnId) -> Option<&mut Connection> {
+        match self.conns.get_mut(id.idx()) {
+            Some(ref conn) if conn.id != id => {
+                info!("dropping message for stale connection {:?}, slot now held by {:?}", id, conn.id);
+                self.metrics.error(Failure::StaleResponse);
+                None
+            },
+            Some(conn) => Some(conn),
+            None => {
+                info!("unable to look up connection {:?}", id);
+                None
+            }
+        }
     }
 
     fn lookup_conn(&mut self, conn_idx: usize) -> Option<&mut Connection> {
@@ -233,4 +731,97 @@ impl Server {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net;
+    use std::sync::mpsc::channel;
+    use mio::Poll;
+    use mio::net::{TcpListener, TcpStream};
+    use byteorder::{ByteOrder, BigEndian};
+    use std::sync::Arc;
+    use bytes::Bytes;
+    use metrics::{Metrics, Failure};
+    use worker::{self, MsgBuf};
+    use super::{Server, conn_token, token_conn, LISTENER, WRITE_PIPELINE, READ_BACKLOG, TASK_WAKEUPS};
+
+    fn socket_pair(listener: &net::TcpListener) -> (net::TcpStream, TcpStream) {
+        let client = net::TcpStream::connect(listener.local_addr().expect("no local address"))
+            .expect("couldn't connect");
+        let (sock, _) = listener.accept().expect("couldn't accept");
+        (client, TcpStream::from_stream(sock).expect("couldn't convert socket"))
+    }
+
+    #[test]
+    fn stale_response_dropped_after_slot_reuse() {
+        let mut poll = Poll::new().expect("couldn't create poll");
+        let server_sock = TcpListener::bind(&"127.0.0.1:0".parse().expect("bad address"))
+            .expect("couldn't bind");
+        let (source, sink) = worker::write_pipeline();
+        let mut server = Server::new(0, server_sock, vec!{}, source, None, 64, Arc::new(Metrics::new(0)));
+
+        let listener = net::TcpListener::bind("127.0.0.1:0").expect("couldn't bind");
+        let (_old_client, old_sock) = socket_pair(&listener);
+        let (mut new_client, new_sock) = socket_pair(&listener);
+
+        let old = server.add_conn(old_sock);
+        server.remove_conn(old.idx());
+        let new = server.add_conn(new_sock);
+        assert_eq!(old.idx(), new.idx());
+        assert_ne!(old, new);
+        server.conns[new.idx()].register(&mut poll, true).expect("couldn't register");
+
+        // a worker answering the closed connection races with the new client taking its slot
+        sink.send_message(MsgBuf::new(old, Bytes::from_static(b"secret"))).expect("couldn't send");
+        sink.send_message(MsgBuf::new(new, Bytes::from_static(b"hello"))).expect("couldn't send");
+        server.handle_writes(&mut poll);
+
+        assert_eq!(server.metrics.errors(Failure::StaleResponse), 1);
+
+        let mut len_buf = [0u8; 8];
+        new_client.read_exact(&mut len_buf).expect("couldn't read length");
+        let mut buf = vec![0u8; BigEndian::read_u64(&len_buf) as usize];
+        new_client.read_exact(&mut buf).expect("couldn't read message");
+        assert_eq!(buf, b"hello");
+    }
+
+    #[test]
+    fn requests_from_hung_up_peers_are_dropped() {
+        let mut poll = Poll::new().expect("couldn't create poll");
+        let server_sock = TcpListener::bind(&"127.0.0.1:0".parse().expect("bad address"))
+            .expect("couldn't bind");
+        let (source, _sink) = worker::write_pipeline();
+        let (tx, rx) = channel();
+        let mut server = Server::new(0, server_sock, vec!{tx}, source, None, 64, Arc::new(Metrics::new(1)));
+
+        let listener = net::TcpListener::bind("127.0.0.1:0").expect("couldn't bind");
+        let (mut client, sock) = socket_pair(&listener);
+        let id = server.add_conn(sock);
+        server.conns[id.idx()].register(&mut poll, true).expect("couldn't register");
+        let info = server.conns[id.idx()].info.clone();
+
+        client.write_all(b"\0\0\0\0\0\0\0\x02hi").expect("couldn't write");
+        drop(client);
+        assert!(server.dispatch_messages(id.idx(), true, &mut poll).expect("couldn't dispatch"));
+
+        assert!(rx.try_recv().is_err());
+        assert_eq!(info.in_flight(), 0);
+        assert!(!server.conns.contains(id.idx()));
+        assert_eq!(server.metrics.frames_in.get(), 1);
+    }
+
+    #[test]
+    fn connection_tokens_never_alias_control_tokens() {
+        for conn_idx in &[0, 1, 2, 111_111, 10_000_000] {
+            let token = conn_token(*conn_idx);
+            assert!(token != LISTENER && token != WRITE_PIPELINE && token != READ_BACKLOG && token != TASK_WAKEUPS);
+            assert_eq!(token_conn(token), Some(*conn_idx));
+        }
+        assert_eq!(token_conn(LISTENER), None);
+        assert_eq!(token_conn(WRITE_PIPELINE), None);
+        assert_eq!(token_conn(READ_BACKLOG), None);
+        assert_eq!(token_conn(TASK_WAKEUPS), None);
+    }
+}
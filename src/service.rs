@@ -0,0 +1,147 @@
+use bytes::Bytes;
+
+use ::MessageHandler;
+use worker::Context;
+use errors::*;
+
+// Turns frames into requests and responses into frames, independent of what the requests do.
+pub trait Codec<Req, Resp>: Sync {
+    fn decode(&self, buf: Bytes) -> Result<Req>;
+    fn encode(&self, msg: Resp) -> Result<Bytes>;
+}
+
+// The business logic half of a MessageHandler, working on typed requests only.
+pub trait Service: Sync {
+    type Req;
+    type Resp;
+    fn process(&self, msg: Self::Req) -> Result<Self::Resp>;
+    fn process_with_context(&self, msg: Self::Req, _ctx: &Context) -> Result<Self::Resp> {
+        self.process(msg)
+    }
+}
+
+// A MessageHandler made of a codec and a service, so one codec can be shared by any number of
+// services. `new` is const so the result can be a static and passed to bootstrap.
+pub struct ServiceHandler<C, S> {
+    codec: C,
+    service: S,
+}
+
+impl<C, S> ServiceHandler<C, S> {
+    pub const fn new(codec: C, service: S) -> ServiceHandler<C, S> {
+        ServiceHandler {
+            codec,
+            service,
+        }
+    }
+
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    pub fn service(&self) -> &S {
+        &self.service
+    }
+}
+
+impl<C, S> MessageHandler for ServiceHandler<C, S>
+        where S: Service, C: Codec<S::Req, S::Resp> {
+    type Req = S::Req;
+    type Resp = S::Resp;
+
+    fn process(&self, msg: S::Req) -> Result<S::Resp> {
+        self.service.process(msg)
+    }
+
+    fn process_with_context(&self, msg: S::Req, ctx: &Context) -> Result<S::Resp> {
+        self.service.process_with_context(msg, ctx)
+    }
+
+    fn serialize(&self, msg: S::Resp) -> Result<Bytes> {
+        self.codec.encode(msg)
+    }
+
+    fn deserialize(&self, buf: Bytes) -> Result<S::Req> {
+        self.codec.decode(buf)
+    }
+}
+
+// Hands frames through untouched.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RawCodec;
+
+impl Codec<Bytes, Bytes> for RawCodec {
+    fn decode(&self, buf: Bytes) -> Result<Bytes> {
+        Ok(buf)
+    }
+
+    fn encode(&self, msg: Bytes) -> Result<Bytes> {
+        Ok(msg)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Utf8Codec;
+
+impl Codec<String, String> for Utf8Codec {
+    fn decode(&self, buf: Bytes) -> Result<String> {
+        match String::from_utf8(buf.to_vec()) {
+            Ok(msg) => Ok(msg),
+            Err(e) => Err(e.utf8_error()).chain_err(|| "message is not valid utf8"),
+        }
+    }
+
+    fn encode(&self, msg: String) -> Result<Bytes> {
+        Ok(msg.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use ::MessageHandler;
+    use errors::*;
+    use super::{Codec, Service, ServiceHandler, Utf8Codec};
+
+    struct Reverser;
+
+    impl Service for Reverser {
+        type Req = String;
+        type Resp = String;
+
+        fn process(&self, msg: String) -> Result<String> {
+            Ok(msg.chars().rev().collect())
+        }
+    }
+
+    struct Shouter;
+
+    impl Service for Shouter {
+        type Req = String;
+        type Resp = String;
+
+        fn process(&self, msg: String) -> Result<String> {
+            Ok(msg.to_uppercase())
+        }
+    }
+
+    static REVERSER: ServiceHandler<Utf8Codec, Reverser> = ServiceHandler::new(Utf8Codec, Reverser);
+    static SHOUTER: ServiceHandler<Utf8Codec, Shouter> = ServiceHandler::new(Utf8Codec, Shouter);
+
+    #[test]
+    fn services_work_without_bytes() {
+        assert_eq!(Reverser.process("abc".to_owned()).expect("couldn't process"), "cba");
+    }
+
+    #[test]
+    fn one_codec_serves_many_services() {
+        let handle = |handler: &dyn MessageHandler<Req=String, Resp=String>, msg: &'static [u8]| {
+            let req = handler.deserialize(Bytes::from_static(msg)).expect("couldn't deserialize");
+            handler.serialize(handler.process(req).expect("couldn't process")).expect("couldn't serialize")
+        };
+
+        assert_eq!(handle(&REVERSER, b"abc"), Bytes::from_static(b"cba"));
+        assert_eq!(handle(&SHOUTER, b"abc"), Bytes::from_static(b"ABC"));
+        assert!(Utf8Codec.decode(Bytes::from_static(&[0xff, 0xfe])).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use byteorder::{ByteOrder, BigEndian};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use worker::{MessageSink, MsgBuf, Context};
+use connection::ConnId;
+use executor::Pending;
+use errors::*;
+
+pub const CORRELATION_LEN: usize = 8;
+pub const FLAG_MORE: u8 = 0;
+pub const FLAG_END: u8 = 1;
+// broadcasts and published messages, which answer no request and carry PUSH_ID
+pub const FLAG_PUSH: u8 = 2;
+pub const PUSH_ID: u64 = 0;
+
+pub trait StreamingMessageHandler: Sync {
+    type Req;
+    type Resp;
+    fn process(&self, msg: Self::Req, ctx: &Context, responses: &mut ResponseSender<Self::Resp>) -> Result<()>;
+    fn serialize(&self, msg: Self::Resp) -> Result<Bytes>;
+    fn deserialize(&self, buf: Bytes) -> Result<Self::Req>;
+}
+
+pub struct ResponseSender<'a, 'b: 'a, O: 'a> {
+    serialize: &'a dyn Fn(O) -> Result<Bytes>,
+    responses: &'a mut Responses<'b>,
+}
+
+impl<'a, 'b, O> ResponseSender<'a, 'b, O> {
+    pub fn new(serialize: &'a dyn Fn(O) -> Result<Bytes>, responses: &'a mut Responses<'b>) -> ResponseSender<'a, 'b, O> {
+        ResponseSender {
+            serialize,
+            responses,
+        }
+    }
+
+    pub fn send(&mut self, msg: O) -> Result<()> {
+        let buf = (self.serialize)(msg)?;
+        self.responses.send(buf)
+    }
+
+    pub fn sent(&self) -> usize {
+        self.responses.sent()
+    }
+}
+
+// Where finished response frames go: the write pipeline when running on a worker thread, or
+// straight back to the server when requests are processed on the I/O thread.
+pub trait Outlet {
+    fn send_message(&mut self, msg: MsgBuf) -> Result<()>;
+
+    // Called in the middle of a response stream, for outlets that would otherwise hold on to
+    // what was sent until the handler is done.
+    fn flush(&mut self, _conn: ConnId) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Outlet for &MessageSink {
+    fn send_message(&mut self, msg: MsgBuf) -> Result<()> {
+        MessageSink::send_message(self, msg)
+    }
+}
+
+impl Outlet for Vec<MsgBuf> {
+    fn send_message(&mut self, msg: MsgBuf) -> Result<()> {
+        self.push(msg);
+        Ok(())
+    }
+}
+
+// Byte level side of a reply stream. When correlation is enabled every response body is
+// prefixed with the request's id and a flag byte, and the stream is closed with FLAG_END.
+pub struct Responses<'a> {
+    out: &'a mut dyn Outlet,
+    conn: ConnId,
+    correlation: Option<u64>,
+    sent: usize,
+    bytes: usize,
+    finished: bool,
+    closed: bool,
+    // set by async handlers, whose response comes later
+    pending: Option<Pending>,
+}
+
+impl<'a> Responses<'a> {
+    pub fn new(out: &'a mut dyn Outlet, conn: ConnId, correlation: Option<u64>) -> Responses<'a> {
+        Responses {
+            out,
+            conn,
+            correlation,
+            sent: 0,
+            bytes: 0,
+            finished: false,
+            closed: false,
+            pending: None,
+        }
+    }
+
+    pub fn send(&mut self, buf: Bytes) -> Result<()> {
+        self.write(buf, FLAG_MORE)?;
+        if let Err(e) = self.out.flush(self.conn) {
+            self.closed = true;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn send_last(&mut self, buf: Bytes) -> Result<()> {
+        self.write(buf, FLAG_END)?;
+        self.finished = true;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<()> {
+        if self.finished || self.correlation.is_none() {
+            return Ok(());
+        }
+        self.send_last(Bytes::new())
+    }
+
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    // Bytes queued so far, including the correlation prefixes.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn defer(&mut self, future: Pending) {
+        self.pending = Some(future);
+    }
+
+    pub fn take_pending(&mut self) -> Option<Pending> {
+        self.pending.take()
+    }
+
+    fn write(&mut self, buf: Bytes, flag: u8) -> Result<()> {
+        if self.finished {
+            return Err("response stream already finished".into());
+        }
+
+        let buf = match self.correlation {
+            Some(id) => frame_response(id, flag, &buf),
+            None => buf,
+        };
+
+        let len = buf.len();
+        match self.out.send_message(MsgBuf::new(self.conn, buf)) {
+            Ok(()) => {
+                self.sent += 1;
+                self.bytes += len;
+                Ok(())
+            },
+            Err(e) => {
+                self.closed = true;
+                Err(e)
+            }
+        }
+    }
+}
+
+pub fn split_correlation(buf: Bytes) -> Result<(u64, Bytes)> {
+    if buf.len() < CORRELATION_LEN {
+        return Err("message too short to carry a correlation id".into());
+    }
+    let id = BigEndian::read_u64(&buf[..CORRELATION_LEN]);
+    Ok((id, buf.slice(CORRELATION_LEN..)))
+}
+
+pub fn frame_response(id: u64, flag: u8, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(CORRELATION_LEN + 1 + payload.len());
+    buf.put_u64(id);
+    buf.put_u8(flag);
+    buf.extend_from_slice(payload);
+    buf.freeze()
+}
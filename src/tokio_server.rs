@@ -0,0 +1,514 @@
+// A server running as tasks on an existing tokio runtime, behind the `tokio` feature. Every
+// connection is one task that reads frames, runs them through the same Processor as the mio
+// server on the runtime's blocking pool and writes the responses back.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io::{self, ErrorKind as IoErrorKind};
+use std::mem;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{self, Poll};
+use std::time::{Duration, Instant};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{self as tokio_time, Sleep};
+
+use ::Config;
+use connection::{self, ConnId, ConnInfo, LEN_PREFIX};
+use handler::Dispatch;
+use metrics::{Metrics, Stats, Failure};
+use pubsub::{Publisher, Topics};
+use access_log::RequestLog;
+use trace::ConnSpan;
+use stream::Outlet;
+use worker::{self, MessageSink, MsgBuf, Outbound, Processor, Progress, Request, Task};
+use errors::*;
+
+const READ_CHUNK: usize = 16 * 1024;
+// how much a connection reads ahead while an async request holds up the ones behind it
+const READ_AHEAD: usize = 4 * READ_CHUNK;
+// how long accepting pauses after an error, like running out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+pub struct TokioServer {
+    local_addr: SocketAddr,
+    publisher: Publisher,
+    sink: MessageSink,
+    metrics: Arc<Metrics>,
+    stop: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+impl TokioServer {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn publisher(&self) -> Publisher {
+        self.publisher.clone()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.metrics.stats()
+    }
+
+    // Stops accepting and closes every open connection.
+    pub fn shutdown(&self) {
+        let stop = match self.stop.lock() {
+            Ok(mut stop) => stop.take(),
+            Err(_) => None,
+        };
+        if let Some(stop) = stop {
+            let _ = stop.send(());
+            if let Err(e) = self.sink.send(Outbound::Shutdown) {
+                warn!("unable to stop the publisher task, {:?}", e);
+            }
+        }
+    }
+}
+
+// Must be called from within a tokio runtime with its I/O and time drivers enabled, which the
+// server's tasks are spawned on.
+pub fn serve(listener: TcpListener, config: &Config, handler: Arc<dyn Dispatch>) -> Result<TokioServer> {
+    let runtime = Handle::try_current().chain_err(|| "the tokio backend must be started inside a runtime")?;
+    let local_addr = listener.local_addr()?;
+    let metrics = Arc::new(Metrics::new(0));
+
+    let (outbound, sink) = worker::channel_pipeline();
+    let routes = Arc::new(Mutex::new(Routes::default()));
+    runtime.spawn(Router {
+        outbound,
+        routes: routes.clone(),
+    });
+
+    let publisher = Publisher::new(sink.clone(), config.correlation);
+    let log = RequestLog::start(config)?;
+    let processor = Arc::new(Processor::new(handler, publisher.clone(), metrics.clone(), config.correlation,
+        log, config.request_timeout));
+
+    let (stop_tx, stop_rx) = oneshot::channel();
+    runtime.spawn(Acceptor {
+        listener,
+        processor,
+        metrics: metrics.clone(),
+        read_budget: config.read_budget,
+        routes,
+        stop: Some(stop_rx),
+        backoff: None,
+        conns: Vec::new(),
+        next_idx: 0,
+    });
+    info!("tokio server starting on {}", local_addr);
+
+    Ok(TokioServer {
+        local_addr,
+        publisher,
+        sink,
+        metrics,
+        stop: Mutex::new(Some(stop_tx)),
+    })
+}
+
+// Where broadcasts and published frames go. Each connection task has a channel, and the
+// topics are kept the same way the mio server keeps them.
+#[derive(Default)]
+struct Routes {
+    conns: HashMap<ConnId, mpsc::UnboundedSender<Bytes>>,
+    topics: Topics,
+}
+
+impl Routes {
+    fn send(&self, id: ConnId, buf: Bytes) {
+        if let Some(conn) = self.conns.get(&id) {
+            // fails only while the connection is closing
+            let _ = conn.send(buf);
+        }
+    }
+}
+
+// Hands the frames that come through the write pipeline to their connections' channels.
+struct Router {
+    outbound: mpsc::UnboundedReceiver<Outbound>,
+    routes: Arc<Mutex<Routes>>,
+}
+
+impl Router {
+    // Returns false once the server is shut down.
+    fn route(&self, out: Outbound) -> Result<bool> {
+        let mut routes = match self.routes.lock() {
+            Ok(routes) => routes,
+            Err(_) => return Err("connection routes poisoned".into()),
+        };
+        match out {
+            Outbound::Reply(msg) => routes.send(msg.conn, msg.buf),
+            Outbound::Broadcast(buf) => {
+                debug!("broadcasting message to {} connections", routes.conns.len());
+                for conn in routes.conns.values() {
+                    let _ = conn.send(buf.clone());
+                }
+            },
+            Outbound::Publish(topic, buf) => {
+                let conns = routes.topics.subscribers(&topic);
+                debug!("publishing message on topic {} to {} connections", topic, conns.len());
+                for conn in conns {
+                    routes.send(conn, buf.clone());
+                }
+            },
+            Outbound::Subscribe(conn, topic) => {
+                if routes.conns.contains_key(&conn) {
+                    routes.topics.subscribe(conn, topic);
+                } else {
+                    info!("ignoring subscription to {} for closed connection {:?}", topic, conn);
+                }
+            },
+            Outbound::Unsubscribe(conn, topic) => routes.topics.unsubscribe(conn, &topic),
+            // there is no admin port here, dropping the reply channel fails the command
+            Outbound::ListConnections(_) | Outbound::Close(..) | Outbound::ClosePeer(..) => {},
+            Outbound::Shutdown => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+impl Future for Router {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<()> {
+        let this = self.get_mut();
+        loop {
+            let out = match this.outbound.poll_recv(cx) {
+                Poll::Ready(Some(out)) => out,
+                // every sink is gone, so nothing can be published any more
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => return Poll::Pending,
+            };
+            match this.route(out) {
+                Ok(true) => {},
+                Ok(false) => {
+                    info!("publisher task exiting");
+                    return Poll::Ready(());
+                },
+                Err(e) => {
+                    error!("publisher task failed, {:?}", e);
+                    return Poll::Ready(());
+                },
+            }
+        }
+    }
+}
+
+struct Acceptor {
+    listener: TcpListener,
+    processor: Arc<Processor>,
+    metrics: Arc<Metrics>,
+    read_budget: usize,
+    routes: Arc<Mutex<Routes>>,
+    // None once the TokioServer is dropped, which leaves the server running
+    stop: Option<oneshot::Receiver<()>>,
+    // set after an accept error, which would most likely just happen again right away
+    backoff: Option<Pin<Box<Sleep>>>,
+    conns: Vec<JoinHandle<()>>,
+    next_idx: usize,
+}
+
+impl Future for Acceptor {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<()> {
+        let this = self.get_mut();
+
+        let stopped = match this.stop {
+            Some(ref mut stop) => match Pin::new(stop).poll(cx) {
+                Poll::Ready(Ok(())) => Some(true),
+                Poll::Ready(Err(_)) => Some(false),
+                Poll::Pending => None,
+            },
+            None => None,
+        };
+        match stopped {
+            Some(true) => {
+                info!("tokio server shutting down");
+                for conn in &this.conns {
+                    conn.abort();
+                }
+                return Poll::Ready(());
+            },
+            Some(false) => this.stop = None,
+            None => {},
+        }
+
+        loop {
+            if let Some(ref mut backoff) = this.backoff {
+                match backoff.as_mut().poll(cx) {
+                    Poll::Ready(()) => this.backoff = None,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+
+            match this.listener.poll_accept(cx) {
+                Poll::Ready(Ok((sock, peer))) => {
+                    if let Err(e) = sock.set_nodelay(true) {
+                        warn!("unable to set nodelay for {}: {:?}", peer, e);
+                    }
+                    // ids are unique for the server's lifetime, so the index doubles as the generation
+                    let id = ConnId::new(0, this.next_idx, this.next_idx as u64);
+                    this.next_idx += 1;
+                    this.metrics.connections_accepted.inc();
+                    this.metrics.active_connections.inc();
+
+                    this.conns.retain(|conn| !conn.is_finished());
+                    this.conns.push(::tokio::spawn(Conn::new(sock, id, Some(peer), this.processor.clone(),
+                        this.metrics.clone(), this.read_budget, this.routes.clone())));
+                },
+                Poll::Ready(Err(e)) => {
+                    this.metrics.error(Failure::Accept);
+                    error!("failed to accept new connection, pausing for {:?}: {:?}", ACCEPT_BACKOFF, e);
+                    this.backoff = Some(Box::pin(tokio_time::sleep(ACCEPT_BACKOFF)));
+                },
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+// Responses go to the connection task over its channel as they are sent, so a streaming
+// handler's frames are written while it carries on.
+impl Outlet for mpsc::UnboundedSender<Bytes> {
+    fn send_message(&mut self, msg: MsgBuf) -> Result<()> {
+        match self.send(msg.buf) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(format!("connection {:?} is closed", msg.conn).into()),
+        }
+    }
+}
+
+// Requests read together are started together on the blocking pool, so synchronous handlers
+// don't hold up the runtime's other tasks.
+struct Batch {
+    reqs: VecDeque<Request>,
+    out: mpsc::UnboundedSender<Bytes>,
+    task: Option<Box<Task>>,
+}
+
+// Starts requests in order until an async handler leaves one pending, and hands back the rest
+// for once it is done.
+fn start_batch(processor: &Processor, mut batch: Batch) -> Batch {
+    while let Some(req) = batch.reqs.pop_front() {
+        if let Progress::Pending(task) = processor.start(req, &mut batch.out) {
+            batch.task = Some(task);
+            break;
+        }
+    }
+    batch
+}
+
+// Requests on a connection are handled one after the other, so an async handler's request
+// holds up the ones behind it until it is done. If the peer hangs up first, its future is
+// dropped unfinished.
+struct Conn {
+    stream: TcpStream,
+    id: ConnId,
+    info: Arc<ConnInfo>,
+    span: ConnSpan,
+    processor: Arc<Processor>,
+    metrics: Arc<Metrics>,
+    read_budget: usize,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    // read but not yet started
+    queued: VecDeque<Request>,
+    running: Option<JoinHandle<Batch>>,
+    task: Option<Box<Task>>,
+    // responses, broadcasts and published messages, in the order they were sent
+    frames: mpsc::UnboundedReceiver<Bytes>,
+    frames_tx: mpsc::UnboundedSender<Bytes>,
+    routes: Arc<Mutex<Routes>>,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl Conn {
+    fn new(stream: TcpStream, id: ConnId, peer: Option<SocketAddr>, processor: Arc<Processor>,
+            metrics: Arc<Metrics>, read_budget: usize, routes: Arc<Mutex<Routes>>) -> Conn {
+        let (frames_tx, frames) = mpsc::unbounded_channel();
+        match routes.lock() {
+            Ok(mut routes) => {
+                routes.conns.insert(id, frames_tx.clone());
+            },
+            Err(_) => warn!("connection routes poisoned, {:?} won't get published messages", id),
+        }
+
+        Conn {
+            stream,
+            id,
+            info: Arc::new(ConnInfo::new(peer)),
+            span: ConnSpan::new(id, peer),
+            processor,
+            metrics,
+            read_budget,
+            read_buf: BytesMut::with_capacity(READ_CHUNK),
+            write_buf: BytesMut::new(),
+            queued: VecDeque::new(),
+            running: None,
+            task: None,
+            frames,
+            frames_tx,
+            routes,
+            bytes_in: 0,
+            bytes_out: 0,
+        }
+    }
+
+    // Returns Ready once the peer has closed the connection. Loops for as long as anything
+    // happens, so every pending branch has registered for a wakeup when it returns Pending.
+    fn drive(&mut self, cx: &mut task::Context) -> io::Result<Poll<()>> {
+        let mut budget = self.read_budget;
+        loop {
+            let mut progress = false;
+
+            if let Some(mut running) = self.running.take() {
+                match Pin::new(&mut running).poll(cx) {
+                    Poll::Ready(Ok(batch)) => {
+                        let Batch { reqs, task, .. } = batch;
+                        self.queued = reqs;
+                        self.task = task;
+                        progress = true;
+                    },
+                    Poll::Ready(Err(e)) => return Err(io::Error::other(e)),
+                    Poll::Pending => self.running = Some(running),
+                }
+            }
+
+            if let Some(mut task) = self.task.take() {
+                match self.processor.poll(&mut task, cx) {
+                    Poll::Ready(result) => {
+                        self.processor.complete(*task, result, &mut self.frames_tx);
+                        progress = true;
+                    },
+                    Poll::Pending => self.task = Some(task),
+                }
+            }
+
+            if self.task.is_none() && self.running.is_none() {
+                while budget > 0 {
+                    let buf = match connection::next_frame(&mut self.read_buf) {
+                        Some(buf) => buf,
+                        None => break,
+                    };
+                    budget -= 1;
+                    progress = true;
+                    self.metrics.frames_in.inc();
+                    self.queued.push_back(Request::new(MsgBuf::new(self.id, buf), Instant::now(),
+                        self.span.request(), self.info.clone()));
+                }
+
+                if !self.queued.is_empty() {
+                    let batch = Batch {
+                        reqs: mem::take(&mut self.queued),
+                        out: self.frames_tx.clone(),
+                        task: None,
+                    };
+                    let processor = self.processor.clone();
+                    self.running = Some(::tokio::task::spawn_blocking(move || start_batch(&processor, batch)));
+                    progress = true;
+                }
+            }
+
+            while let Poll::Ready(Some(buf)) = self.frames.poll_recv(cx) {
+                queue_frame(&mut self.write_buf, &buf, &self.metrics);
+                progress = true;
+            }
+
+            while !self.write_buf.is_empty() {
+                match self.stream.poll_write_ready(cx)? {
+                    Poll::Ready(()) => match self.stream.try_write(&self.write_buf) {
+                        Ok(n) => {
+                            self.write_buf.advance(n);
+                            self.bytes_out += n as u64;
+                            self.metrics.bytes_out.add(n as u64);
+                            progress = true;
+                        },
+                        Err(ref e) if e.kind() == IoErrorKind::WouldBlock => {},
+                        Err(e) => return Err(e),
+                    },
+                    Poll::Pending => break,
+                }
+            }
+
+            if budget == 0 {
+                // give other tasks on this thread a turn before carrying on
+                cx.waker().wake_by_ref();
+                return Ok(Poll::Pending);
+            }
+
+            // keep reading while requests are running too, so a peer hanging up drops them
+            let busy = self.task.is_some() || self.running.is_some();
+            if !busy || self.read_buf.len() < READ_AHEAD {
+                match self.stream.poll_read_ready(cx)? {
+                    Poll::Ready(()) => {
+                        self.read_buf.reserve(READ_CHUNK);
+                        match self.stream.try_read_buf(&mut self.read_buf) {
+                            Ok(0) => return Ok(Poll::Ready(())),
+                            Ok(n) => {
+                                self.bytes_in += n as u64;
+                                self.metrics.bytes_in.add(n as u64);
+                            },
+                            Err(ref e) if e.kind() == IoErrorKind::WouldBlock => {},
+                            Err(e) => return Err(e),
+                        }
+                        progress = true;
+                    },
+                    Poll::Pending => {},
+                }
+            }
+
+            if !progress {
+                return Ok(Poll::Pending);
+            }
+        }
+    }
+}
+
+fn queue_frame(write_buf: &mut BytesMut, buf: &[u8], metrics: &Metrics) {
+    write_buf.reserve(LEN_PREFIX + buf.len());
+    write_buf.put_u64(buf.len() as u64);
+    write_buf.put_slice(buf);
+    metrics.frames_out.inc();
+}
+
+impl Future for Conn {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<()> {
+        let this = self.get_mut();
+        match this.drive(cx) {
+            Ok(Poll::Ready(())) => {
+                info!("connection {:?} closed by peer", this.id);
+                Poll::Ready(())
+            },
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(e) => {
+                this.metrics.error(Failure::Read);
+                warn!("closing connection {:?}: {:?}", this.id, e);
+                Poll::Ready(())
+            },
+        }
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        if let Ok(mut routes) = self.routes.lock() {
+            routes.conns.remove(&self.id);
+            routes.topics.remove_conn(self.id);
+        }
+        self.info.close();
+        self.span.closed(self.bytes_in, self.bytes_out);
+        self.metrics.connections_closed.inc();
+        self.metrics.active_connections.dec();
+    }
+}
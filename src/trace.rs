@@ -0,0 +1,184 @@
+// Spans for the optional `tracing` feature. Without it these are empty types whose methods do
+// nothing, so the rest of the crate doesn't need cfg attributes around every call.
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tracing;
+    use tracing::{Span, field};
+    use connection::ConnId;
+
+    fn micros(d: Duration) -> u64 {
+        d.as_secs() * 1_000_000 + u64::from(d.subsec_micros())
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct ConnSpan(Span);
+
+    impl ConnSpan {
+        pub fn new(id: ConnId, peer: Option<SocketAddr>) -> ConnSpan {
+            ConnSpan(tracing::info_span!("connection", io = id.io() as u64, idx = id.idx() as u64,
+                gen = id.gen(), peer = ?peer))
+        }
+
+        // Created on the I/O thread as the frame is read and carried along to the worker, so
+        // whatever the worker records ends up under the connection it came from.
+        pub fn request(&self) -> RequestSpan {
+            RequestSpan(Box::new(tracing::info_span!(parent: &self.0, "request", queue_us = field::Empty,
+                handler_us = field::Empty, ok = field::Empty)))
+        }
+
+        pub fn closed(&self, bytes_in: u64, bytes_out: u64) {
+            tracing::debug!(parent: &self.0, bytes_in, bytes_out, "connection closed");
+        }
+    }
+
+    // Boxed because it travels inside WorkerMsg, which the channel send errors carry around.
+    #[derive(Debug, Clone)]
+    pub struct RequestSpan(Box<Span>);
+
+    impl RequestSpan {
+        pub fn in_scope<T, F: FnOnce() -> T>(&self, f: F) -> T {
+            self.0.in_scope(f)
+        }
+
+        pub fn queued(&self, wait: Duration) {
+            self.0.record("queue_us", micros(wait));
+        }
+
+        pub fn handled(&self, took: Duration, ok: bool) {
+            self.0.record("handler_us", micros(took));
+            self.0.record("ok", ok);
+            tracing::debug!(parent: &*self.0, "request finished");
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use connection::ConnId;
+
+    #[derive(Debug, Clone)]
+    pub struct ConnSpan;
+
+    impl ConnSpan {
+        pub fn new(_id: ConnId, _peer: Option<SocketAddr>) -> ConnSpan {
+            ConnSpan
+        }
+
+        pub fn request(&self) -> RequestSpan {
+            RequestSpan
+        }
+
+        pub fn closed(&self, _bytes_in: u64, _bytes_out: u64) {}
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct RequestSpan;
+
+    impl RequestSpan {
+        pub fn in_scope<T, F: FnOnce() -> T>(&self, f: F) -> T {
+            f()
+        }
+
+        pub fn queued(&self, _wait: Duration) {}
+
+        pub fn handled(&self, _took: Duration, _ok: bool) {}
+    }
+}
+
+pub use self::imp::{ConnSpan, RequestSpan};
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+    use tracing;
+    use tracing::{Event, Id, Metadata, Subscriber};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Record};
+    use connection::ConnId;
+    use super::ConnSpan;
+
+    #[derive(Debug, Default)]
+    struct Captured {
+        name: &'static str,
+        parent: Option<u64>,
+        fields: HashMap<String, String>,
+    }
+
+    struct Fields<'a>(&'a mut HashMap<String, String>);
+
+    impl<'a> Visit for Fields<'a> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.insert(field.name().to_owned(), format!("{:?}", value));
+        }
+    }
+
+    // Just enough of a subscriber to see which spans were created under which parent and
+    // what was recorded on them.
+    #[derive(Default)]
+    struct Recorder {
+        next_id: AtomicU64,
+        spans: Arc<Mutex<HashMap<u64, Captured>>>,
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &Attributes) -> Id {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+            let mut span = Captured {
+                name: attrs.metadata().name(),
+                parent: attrs.parent().map(Id::into_u64),
+                ..Captured::default()
+            };
+            attrs.record(&mut Fields(&mut span.fields));
+            self.spans.lock().expect("poisoned").insert(id, span);
+            Id::from_u64(id)
+        }
+
+        fn record(&self, id: &Id, values: &Record) {
+            if let Some(span) = self.spans.lock().expect("poisoned").get_mut(&id.into_u64()) {
+                values.record(&mut Fields(&mut span.fields));
+            }
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, _: &Event) {}
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn request_span_nests_under_connection() {
+        let recorder = Recorder::default();
+        let spans = recorder.spans.clone();
+
+        tracing::subscriber::with_default(recorder, || {
+            let conn = ConnSpan::new(ConnId::new(1, 2, 3), "127.0.0.1:4000".parse().ok());
+            let request = conn.request();
+            request.queued(Duration::from_micros(250));
+            request.handled(Duration::from_millis(2), true);
+        });
+
+        let spans = spans.lock().expect("poisoned");
+        let (conn_id, conn) = spans.iter().find(|&(_, span)| span.name == "connection").expect("no connection span");
+        assert_eq!(conn.fields["idx"], "2");
+        assert_eq!(conn.fields["peer"], "Some(127.0.0.1:4000)");
+
+        let request = spans.values().find(|span| span.name == "request").expect("no request span");
+        assert_eq!(request.parent, Some(*conn_id));
+        assert_eq!(request.fields["queue_us"], "250");
+        assert_eq!(request.fields["handler_us"], "2000");
+        assert_eq!(request.fields["ok"], "true");
+    }
+}
@@ -1,60 +1,375 @@
-use ::MessageHandler;
-use std::sync::mpsc::{Sender, Receiver,TryIter};
+use std::cell::Cell;
+use std::net::IpAddr;
+use std::sync::mpsc::Sender;
+use std::task::{self, Waker};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use bytes::Bytes;
+use crossbeam_queue::SegQueue;
+use std::sync::mpsc::Receiver;
 use errors::*;
+use pubsub::Publisher;
+use connection::{ConnId, ConnInfo, ConnectionSnapshot};
+use handler::Dispatch;
+use metrics::{Metrics, Failure};
+use access_log::{RequestLog, RequestRecord, Stage, Stages, Outcome};
+use trace::RequestSpan;
+use stream;
+use stream::{Responses, Outlet};
+use executor::{self, Pending};
+use slab::Slab;
 use mio::{Evented, Poll, Token, Ready, PollOpt, Registration,SetReadiness};
 use std::io::Result as IOResult;
 
-pub fn write_pipeline(sender: Sender<MsgBuf>, receiver: Receiver<MsgBuf>) -> (MessageSource, MessageSink) {
+pub fn write_pipeline() -> (MessageSource, MessageSink) {
     let (registration, set_readiness) = Registration::new2();
-    (MessageSource{registration: registration, receiver: receiver}, MessageSink{sender: sender, set_readiness: set_readiness})
+    let queue = Arc::new(OutboundQueue::new());
+    let source = MessageSource {
+        queue: queue.clone(),
+        registration,
+    };
+    (source, MessageSink{pipes: vec![Pipe::Queue{queue, set_readiness}]})
+}
+
+// A pipeline drained by a task on a tokio runtime, which wakes up as messages arrive on the
+// channel instead of through mio.
+#[cfg(feature = "tokio")]
+pub fn channel_pipeline() -> (::tokio::sync::mpsc::UnboundedReceiver<Outbound>, MessageSink) {
+    let (tx, rx) = ::tokio::sync::mpsc::unbounded_channel();
+    (rx, MessageSink{pipes: vec![Pipe::Channel(tx)]})
 }
 
 #[derive(Debug, Clone)]
 pub struct MsgBuf {
-    pub conn_idx: usize,
-    pub buf: Vec<u8>,
+    pub conn: ConnId,
+    pub buf: Bytes,
 }
 
 impl MsgBuf {
-    pub fn new(conn_idx: usize, buf: Vec<u8>) -> MsgBuf {
+    pub fn new(conn: ConnId, buf: Bytes) -> MsgBuf {
         MsgBuf {
-            conn_idx: conn_idx,
-            buf: buf,
+            conn,
+            buf,
         }
     }
 
-    pub fn shutdown_msg() -> MsgBuf {
-        MsgBuf {
-            conn_idx: 111_111,
-            buf: vec!{},
+}
+
+// A request on its way to a worker, along with when the I/O thread handed it off, the span
+// it is traced under and the connection it came from. Counts as in flight on the connection
+// from creation until a processor has handled it.
+#[derive(Debug)]
+pub struct Request {
+    pub msg: MsgBuf,
+    pub enqueued: Instant,
+    pub span: RequestSpan,
+    pub info: Arc<ConnInfo>,
+}
+
+impl Request {
+    pub fn new(msg: MsgBuf, enqueued: Instant, span: RequestSpan, info: Arc<ConnInfo>) -> Request {
+        info.request_started();
+        Request {
+            msg,
+            enqueued,
+            span,
+            info,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WorkerMsg {
+    Request(Request),
+    // an async handler's future can make progress
+    Wake(usize),
+    Shutdown,
+}
+
+#[derive(Debug, Clone)]
+pub enum Outbound {
+    Reply(MsgBuf),
+    Broadcast(Bytes),
+    Publish(String, Bytes),
+    Subscribe(ConnId, String),
+    Unsubscribe(ConnId, String),
+    // admin commands, each I/O thread answers for the connections it owns
+    ListConnections(Sender<Vec<ConnectionSnapshot>>),
+    Close(ConnId, Sender<bool>),
+    ClosePeer(IpAddr, Sender<usize>),
+    Shutdown,
+}
+
+pub struct Context<'a> {
+    conn: ConnId,
+    cancellation: &'a Cancellation,
+    publisher: &'a Publisher,
+    metrics: &'a Metrics,
+    // the cancellation's deadline, or an earlier one set by a layer for the code inside it
+    deadline: Cell<Option<Instant>>,
+    stages: Cell<Stages>,
+    failure: Cell<Option<Failure>>,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(conn: ConnId, cancellation: &'a Cancellation, publisher: &'a Publisher, metrics: &'a Metrics)
+            -> Context<'a> {
+        Context {
+            conn,
+            cancellation,
+            publisher,
+            metrics,
+            deadline: Cell::new(cancellation.deadline()),
+            stages: Cell::new(Stages::default()),
+            failure: Cell::new(None),
+        }
+    }
+
+    pub fn conn(&self) -> ConnId {
+        self.conn
+    }
+
+    pub fn publisher(&self) -> &Publisher {
+        self.publisher
+    }
+
+    // True once the client has disconnected or the request is past its deadline, so long
+    // running handlers can give up early.
+    pub fn is_cancelled(&self) -> bool {
+        self.reason().is_some()
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline.get()
+    }
+
+    // Runs `f` with the deadline moved up to `deadline`, if that is earlier, and puts the old
+    // one back afterwards. This is how a layer gives the code inside it less time.
+    pub fn with_deadline<T, F>(&self, deadline: Instant, f: F) -> T
+            where F: FnOnce() -> T {
+        let outer = self.deadline.get();
+        match outer {
+            Some(outer) if outer <= deadline => {},
+            _ => self.deadline.set(Some(deadline)),
         }
+        let result = f();
+        self.deadline.set(outer);
+        result
+    }
 
+    // Keeps checking from another thread, against the deadline in effect now.
+    pub fn cancellation(&self) -> Cancellation {
+        Cancellation::new(self.cancellation.info.clone(), self.deadline.get())
+    }
+
+    fn reason(&self) -> Option<Failure> {
+        Cancellation::reason_by(&self.cancellation.info, self.deadline.get())
     }
 }
 
+// Tells whether a request is still worth working on: its connection is open and its deadline,
+// if there is one, hasn't passed.
+#[derive(Debug, Clone)]
+pub struct Cancellation {
+    info: Arc<ConnInfo>,
+    deadline: Option<Instant>,
+}
+
+impl Cancellation {
+    pub fn new(info: Arc<ConnInfo>, deadline: Option<Instant>) -> Cancellation {
+        Cancellation {
+            info,
+            deadline,
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.reason().is_some()
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn reason(&self) -> Option<Failure> {
+        Cancellation::reason_by(&self.info, self.deadline)
+    }
+
+    fn reason_by(info: &ConnInfo, deadline: Option<Instant>) -> Option<Failure> {
+        if info.is_closed() {
+            return Some(Failure::Cancelled);
+        }
+        match deadline {
+            Some(deadline) if Instant::now() >= deadline => Some(Failure::Expired),
+            _ => None,
+        }
+    }
+}
+
+// Runs one stage of handling a request, recording how long it took in the metrics and on the
+// request's context, and counting it as that stage's kind of failure if it errors. Lives here
+// rather than on Context so handlers can't call it.
+pub fn timed<T, F>(ctx: &Context, stage: Stage, f: F) -> Result<T>
+        where F: FnOnce() -> Result<T> {
+    let (histogram, failure) = match stage {
+        Stage::Deserialize => (&ctx.metrics.deserialize, Failure::Deserialize),
+        Stage::Process => (&ctx.metrics.process, Failure::Process),
+        Stage::Serialize => (&ctx.metrics.serialize, Failure::Serialize),
+    };
+
+    let start = Instant::now();
+    let res = f();
+    let elapsed = start.elapsed();
+    histogram.record(elapsed);
+
+    let mut stages = ctx.stages.get();
+    stages.add(stage, elapsed);
+    ctx.stages.set(stages);
+    // a stage nested in another, like serializing each streamed response while processing,
+    // already counted the failure it passes up
+    if res.is_err() && ctx.failure.get().is_none() {
+        // a handler giving up on a cancelled request fails for that reason, not its stage
+        failed(ctx, ctx.reason().unwrap_or(failure));
+    }
+    res
+}
+
+// Counts a failure against the request, the first one recorded is its outcome.
+pub fn failed(ctx: &Context, failure: Failure) {
+    ctx.metrics.error(failure);
+    if ctx.failure.get().is_none() {
+        ctx.failure.set(Some(failure));
+    }
+}
+
+// Lock-free queue from the workers to one I/O thread. `notified` is set by whichever sender
+// finds it clear and cleared by the I/O thread before it drains, so a burst of responses
+// costs a single wakeup. `closed` stands in for a disconnected channel once the I/O thread
+// is gone.
+#[derive(Debug)]
+struct OutboundQueue {
+    queue: SegQueue<Outbound>,
+    notified: AtomicBool,
+    closed: AtomicBool,
+}
+
+impl OutboundQueue {
+    fn new() -> OutboundQueue {
+        OutboundQueue {
+            queue: SegQueue::new(),
+            notified: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Pipe {
+    Queue {
+        queue: Arc<OutboundQueue>,
+        set_readiness: SetReadiness,
+    },
+    #[cfg(feature = "tokio")]
+    Channel(::tokio::sync::mpsc::UnboundedSender<Outbound>),
+}
+
+impl Pipe {
+    fn send(&self, out: Outbound) -> Result<()> {
+        match *self {
+            Pipe::Queue { ref queue, ref set_readiness } => {
+                if queue.closed.load(Ordering::Acquire) {
+                    return Err(ErrorKind::PipelineClosed.into());
+                }
+
+                queue.queue.push(out);
+                if !queue.notified.swap(true, Ordering::AcqRel) {
+                    set_readiness.set_readiness(Ready::writable())?;
+                }
+                Ok(())
+            },
+            #[cfg(feature = "tokio")]
+            Pipe::Channel(ref tx) => match tx.send(out) {
+                Ok(()) => Ok(()),
+                Err(_) => Err(ErrorKind::PipelineClosed.into()),
+            },
+        }
+    }
+}
+
+// Holds one pipe per I/O thread. Anything addressed to a connection goes to the thread that
+// owns it, everything else is fanned out to all of them.
 #[derive(Debug, Clone)]
 pub struct MessageSink {
-    sender: Sender<MsgBuf>,
-    set_readiness: SetReadiness,
+    pipes: Vec<Pipe>,
 }
 
 impl MessageSink {
+    pub fn join(sinks: Vec<MessageSink>) -> MessageSink {
+        MessageSink {
+            pipes: sinks.into_iter().flat_map(|sink| sink.pipes).collect(),
+        }
+    }
+
     pub fn send_message(&self, msg: MsgBuf) -> Result<()> {
-        self.sender.send(msg)?;
-        self.set_readiness.set_readiness(Ready::writable())?;
-        Ok(())
+        self.send(Outbound::Reply(msg))
+    }
+
+    pub fn io_threads(&self) -> usize {
+        self.pipes.len()
+    }
+
+    pub fn send(&self, out: Outbound) -> Result<()> {
+        let io = match out {
+            Outbound::Reply(ref msg) => Some(msg.conn.io()),
+            Outbound::Subscribe(conn, _) | Outbound::Unsubscribe(conn, _) | Outbound::Close(conn, _) => Some(conn.io()),
+            _ => None,
+        };
+
+        match io {
+            Some(io) => {
+                match self.pipes.get(io) {
+                    Some(pipe) => pipe.send(out),
+                    None => Err(format!("no I/O thread {} for outbound message", io).into()),
+                }
+            },
+            None => {
+                for pipe in &self.pipes {
+                    pipe.send(out.clone())?;
+                }
+                Ok(())
+            }
+        }
     }
 }
 
 
 pub struct MessageSource {
-    receiver: Receiver<MsgBuf>,
+    queue: Arc<OutboundQueue>,
     registration: Registration,
 }
 
 impl MessageSource {
-    pub fn try_iter(&self) -> TryIter<MsgBuf> {
-        self.receiver.try_iter()
+    // Takes what was queued when the notified flag was cleared. Anything sent after that
+    // wakes the I/O thread again, so stopping there keeps busy workers from starving reads.
+    pub fn drain(&self) -> Vec<Outbound> {
+        self.queue.notified.store(false, Ordering::Release);
+
+        let queued = self.queue.queue.len();
+        let mut outs = Vec::with_capacity(queued);
+        for _ in 0..queued {
+            match self.queue.queue.pop() {
+                Some(out) => outs.push(out),
+                None => break,
+            }
+        }
+        outs
+    }
+}
+
+impl Drop for MessageSource {
+    fn drop(&mut self) {
+        self.queue.closed.store(true, Ordering::Release);
     }
 }
 
@@ -73,35 +388,297 @@ impl Evented for MessageSource {
     }
 }
 
-pub struct Worker<'a, I: 'a, O: 'a> {
-    handler: &'a MessageHandler<Req=I, Resp=O>,
-    read_rx: Receiver<MsgBuf>,
+// Runs requests through the handler. Owned by each worker thread, or by each server when
+// requests are processed on the I/O thread.
+#[derive(Clone)]
+pub struct Processor {
+    handler: Arc<dyn Dispatch>,
+    publisher: Publisher,
+    metrics: Arc<Metrics>,
+    correlation: bool,
+    log: Option<RequestLog>,
+    timeout: Option<Duration>,
+}
+
+// What became of one request.
+struct Handled {
+    outcome: Outcome,
+    stages: Stages,
+    response_bytes: usize,
+    open: bool,
+}
+
+enum Ran {
+    Handled(Handled),
+    Deferred(Pending, Option<u64>, Stages),
+}
+
+// Where a request stands once its handler has been called.
+pub enum Progress {
+    // false once the outlet is closed
+    Done(bool),
+    Pending(Box<Task>),
+}
+
+// What is needed to account for a request once it is finished.
+struct Started {
+    conn: ConnId,
+    request_bytes: usize,
+    start: Instant,
+    enqueued: Instant,
+    span: RequestSpan,
+    cancellation: Cancellation,
+}
+
+// A request waiting on an async handler.
+pub struct Task {
+    future: Pending,
+    correlation: Option<u64>,
+    stages: Stages,
+    deferred: Instant,
+    started: Started,
+}
+
+impl Processor {
+    pub fn new(handler: Arc<dyn Dispatch>, publisher: Publisher, metrics: Arc<Metrics>, correlation: bool,
+            log: Option<RequestLog>, timeout: Option<Duration>) -> Processor {
+        Processor {
+            handler,
+            publisher,
+            metrics,
+            correlation,
+            log,
+            timeout,
+        }
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn start(&self, req: Request, out: &mut dyn Outlet) -> Progress {
+        let Request { msg, enqueued, span, info } = req;
+        let started = Started {
+            conn: msg.conn,
+            request_bytes: msg.buf.len(),
+            start: Instant::now(),
+            enqueued,
+            span,
+            cancellation: Cancellation::new(info, self.timeout.map(|timeout| enqueued + timeout)),
+        };
+
+        // requests from clients that have gone away, or that waited past their deadline, are
+        // dropped without being handled
+        let handled = match started.cancellation.reason() {
+            Some(failure) => {
+                self.metrics.error(failure);
+                debug!("skipping message from connection {:?}: {}", started.conn, failure.name());
+                self.skip(msg, failure, out)
+            },
+            None => match started.span.in_scope(|| self.run(msg, &started.cancellation, out)) {
+                Ran::Handled(handled) => handled,
+                Ran::Deferred(future, correlation, stages) => return Progress::Pending(Box::new(Task {
+                    future,
+                    correlation,
+                    stages,
+                    deferred: Instant::now(),
+                    started,
+                })),
+            },
+        };
+
+        Progress::Done(self.finish(started, handled))
+    }
+
+    pub fn poll(&self, task: &mut Task, cx: &mut task::Context) -> task::Poll<Result<Bytes>> {
+        let future = &mut task.future;
+        task.started.span.in_scope(|| future.as_mut().poll(cx))
+    }
+
+    // Sends the response of a finished task. Returns false once the outlet is closed.
+    pub fn complete(&self, task: Task, result: Result<Bytes>, out: &mut dyn Outlet) -> bool {
+        let Task { correlation, mut stages, deferred, started, .. } = task;
+        let took = deferred.elapsed();
+        self.metrics.process.record(took);
+        stages.add(Stage::Process, took);
+
+        let mut responses = Responses::new(out, started.conn, correlation);
+        let outcome = match result {
+            Ok(buf) => match responses.send_last(buf) {
+                Ok(()) => Outcome::Ok,
+                Err(_) => Outcome::Closed,
+            },
+            Err(e) => {
+                let failure = started.cancellation.reason().unwrap_or(Failure::Process);
+                self.metrics.error(failure);
+                warn!("unable to handle message: {:?}", e);
+                if let Err(e) = responses.finish() {
+                    warn!("unable to finish response stream: {:?}", e);
+                }
+                Outcome::Failed(failure)
+            }
+        };
+
+        let handled = Handled {
+            outcome,
+            stages,
+            response_bytes: responses.bytes(),
+            open: !responses.is_closed(),
+        };
+        self.finish(started, handled)
+    }
+
+    fn finish(&self, started: Started, handled: Handled) -> bool {
+        let Started { conn, request_bytes, start, enqueued, span, cancellation } = started;
+        span.handled(start.elapsed(), handled.outcome == Outcome::Ok);
+        let info = cancellation.info;
+        info.request_finished();
+
+        if let Some(ref log) = self.log {
+            let mut stages = handled.stages;
+            stages.queue = start.duration_since(enqueued);
+            log.record(RequestRecord {
+                time: SystemTime::now(),
+                conn,
+                peer: info.peer,
+                request_bytes,
+                response_bytes: handled.response_bytes,
+                latency: enqueued.elapsed(),
+                stages,
+                outcome: handled.outcome,
+            });
+        }
+
+        handled.open
+    }
+
+    // A correlated client still waits for the end of an expired request's stream, so it gets
+    // an empty one. Nobody is left to tell about a cancelled request.
+    fn skip(&self, msg: MsgBuf, failure: Failure, out: &mut dyn Outlet) -> Handled {
+        let mut handled = Handled {
+            outcome: Outcome::Failed(failure),
+            stages: Stages::default(),
+            response_bytes: 0,
+            open: true,
+        };
+        if failure != Failure::Expired || !self.correlation {
+            return handled;
+        }
+
+        match stream::split_correlation(msg.buf) {
+            Ok((id, _)) => {
+                let mut responses = Responses::new(out, msg.conn, Some(id));
+                if let Err(e) = responses.finish() {
+                    warn!("unable to finish response stream: {:?}", e);
+                }
+                handled.response_bytes = responses.bytes();
+                handled.open = !responses.is_closed();
+            },
+            Err(e) => warn!("dropping message from connection {:?}: {:?}", msg.conn, e),
+        }
+        handled
+    }
+
+    fn run(&self, msg: MsgBuf, cancellation: &Cancellation, out: &mut dyn Outlet) -> Ran {
+        let (correlation, buf) = if self.correlation {
+            match stream::split_correlation(msg.buf) {
+                Ok((id, buf)) => (Some(id), buf),
+                Err(e) => {
+                    self.metrics.error(Failure::Deserialize);
+                    warn!("dropping message from connection {:?}: {:?}", msg.conn, e);
+                    return Ran::Handled(Handled {
+                        outcome: Outcome::Failed(Failure::Deserialize),
+                        stages: Stages::default(),
+                        response_bytes: 0,
+                        open: true,
+                    });
+                }
+            }
+        } else {
+            (None, msg.buf)
+        };
+
+        let ctx = Context::new(msg.conn, cancellation, &self.publisher, &self.metrics);
+        let mut responses = Responses::new(out, msg.conn, correlation);
+
+        let mut outcome = match self.handler.dispatch(buf, &ctx, &mut responses) {
+            Ok(()) => match responses.take_pending() {
+                Some(future) => return Ran::Deferred(future, correlation, ctx.stages.get()),
+                None => Outcome::Ok,
+            },
+            Err(e) => {
+                warn!("unable to handle message: {:?}", e);
+                Outcome::Failed(ctx.failure.get().unwrap_or(Failure::Process))
+            }
+        };
+
+        if !responses.is_closed() {
+            if let Err(e) = responses.finish() {
+                warn!("unable to finish response stream: {:?}", e);
+            }
+        }
+        if responses.is_closed() && ctx.failure.get().is_none() {
+            outcome = Outcome::Closed;
+        }
+
+        Ran::Handled(Handled {
+            outcome,
+            stages: ctx.stages.get(),
+            response_bytes: responses.bytes(),
+            open: !responses.is_closed(),
+        })
+    }
+}
+
+pub struct Worker {
+    idx: usize,
+    processor: Processor,
+    read_rx: Receiver<WorkerMsg>,
+    wake_tx: Sender<WorkerMsg>,
     sink: MessageSink,
+    // requests waiting on async handlers
+    tasks: Slab<(Box<Task>, Waker)>,
 }
 
-impl<'a, I, O> Worker<'a, I, O> {
-    pub fn new(handler: &'a MessageHandler<Req=I, Resp=O>, read_rx: Receiver<MsgBuf>, 
-            sink: MessageSink) -> Worker<'a, I, O> {
+impl Worker {
+    pub fn new(idx: usize, processor: Processor, read_rx: Receiver<WorkerMsg>, wake_tx: Sender<WorkerMsg>,
+            sink: MessageSink) -> Worker {
         Worker {
-            handler: handler,
-            read_rx: read_rx,
-            sink: sink,
+            idx,
+            processor,
+            read_rx,
+            wake_tx,
+            sink,
+            tasks: Slab::new(),
         }
     }
 
     pub fn run(&mut self) -> Result<()> {
         loop {
             if !self.readloop() {
+                if !self.tasks.is_empty() {
+                    warn!("worker dropping {} unfinished requests", self.tasks.len());
+                }
                 info!("worker exiting");
                 return Ok(());
             }
         }
     }
 
-    fn readloop(&self) -> bool {
+    fn readloop(&mut self) -> bool {
         match self.read_rx.recv() {
-            Ok(buf) => {
-                self.handle_input(buf)
+            Ok(WorkerMsg::Request(req)) => {
+                let metrics = self.processor.metrics();
+                let wait = req.enqueued.elapsed();
+                metrics.worker_queues[self.idx].dec();
+                metrics.queue_wait.record(wait);
+                req.span.queued(wait);
+                self.handle_input(req)
+            },
+            Ok(WorkerMsg::Wake(key)) => self.poll_task(key),
+            Ok(WorkerMsg::Shutdown) => {
+                info!("worker received shutdown");
+                false
             },
             Err(e) => {
                 info!("error receiving input in worker: {:?}. presuming shutdown", e);
@@ -110,50 +687,97 @@ impl<'a, I, O> Worker<'a, I, O> {
         }
     }
 
-    fn handle_input(&self, buf: MsgBuf) -> bool {
-        match self.handler.deserialize(buf.buf) {
-            Ok(req) => {
-                self.process_and_reply(buf.conn_idx, req)
-            },
-            Err(e) => {
-                warn!("unable to deserialize message: {:?}", e);
-                true
+    fn handle_input(&mut self, req: Request) -> bool {
+        let open = match self.processor.start(req, &mut &self.sink) {
+            Progress::Done(open) => open,
+            Progress::Pending(task) => {
+                let entry = self.tasks.vacant_entry();
+                let key = entry.key();
+                entry.insert((task, executor::waker(key, self.wake_tx.clone())));
+                self.poll_task(key)
             }
+        };
+
+        if !open {
+            info!("error sending output in worker. presuming shutdown");
         }
+        open
     }
 
-
-    fn process_and_reply(&self, conn_idx: usize, req: I) -> bool {
-        match self.handler.process(req) {
-            Ok(resp) => {
-                self.serialize_and_write(conn_idx, resp)
+    // Wakeups can outlive their task, and a slot may hold a newer task by then, which is
+    // polled for nothing.
+    fn poll_task(&mut self, key: usize) -> bool {
+        let result = match self.tasks.get_mut(key) {
+            Some(&mut (ref mut task, ref waker)) => {
+                match self.processor.poll(task, &mut task::Context::from_waker(waker)) {
+                    task::Poll::Ready(result) => result,
+                    task::Poll::Pending => return true,
+                }
             },
-            Err(e) => {
-                warn!("unable to process message: {:?}", e);
-                true
-            }
-        }
+            None => return true,
+        };
+
+        let (task, _) = self.tasks.remove(key);
+        self.processor.complete(*task, result, &mut &self.sink)
     }
+}
 
-    fn serialize_and_write(&self, conn_idx: usize, resp: O) -> bool {
-        match self.handler.serialize(resp) {
-            Ok(buf) => {
-                self.write_response(MsgBuf::new(conn_idx, buf))
-            },
-            Err(e) => {
-                warn!("unable to serialize response: {:?}", e);
-                true
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use mio::{Events, Poll, PollOpt, Ready, Token};
+    use bytes::Bytes;
+    use access_log::Stage;
+    use connection::{ConnId, ConnInfo};
+    use metrics::{Metrics, Failure};
+    use pubsub::Publisher;
+    use errors::*;
+    use super::{write_pipeline, timed, Cancellation, Context, MsgBuf};
+
+    fn wakeups(poll: &Poll, events: &mut Events) -> usize {
+        poll.poll(events, Some(Duration::from_millis(10))).expect("couldn't poll")
     }
 
-    fn write_response(&self, buf: MsgBuf) -> bool {
-        match self.sink.send_message(buf) {
-            Ok(()) => true,
-            Err(e) => {
-                info!("error sending output in worker: {:?}. presuming shutdown", e);
-                false
-            }
+    #[test]
+    fn burst_of_sends_wakes_once() {
+        let poll = Poll::new().expect("couldn't create poll");
+        let mut events = Events::with_capacity(16);
+        let (source, sink) = write_pipeline();
+        poll.register(&source, Token(0), Ready::writable(), PollOpt::edge()).expect("couldn't register");
+
+        let conn = ConnId::new(0, 0, 0);
+        for i in 0..100u8 {
+            sink.send_message(MsgBuf::new(conn, Bytes::from(vec![i]))).expect("couldn't send");
         }
+        assert_eq!(wakeups(&poll, &mut events), 1);
+        assert_eq!(source.drain().len(), 100);
+        assert_eq!(wakeups(&poll, &mut events), 0);
+
+        // the flag was cleared by the drain, so the next send wakes the I/O thread again
+        sink.send_message(MsgBuf::new(conn, Bytes::from_static(b"again"))).expect("couldn't send");
+        assert_eq!(wakeups(&poll, &mut events), 1);
+        assert_eq!(source.drain().len(), 1);
+    }
+
+    #[test]
+    fn send_fails_once_source_is_gone() {
+        let (source, sink) = write_pipeline();
+        drop(source);
+        assert!(sink.send_message(MsgBuf::new(ConnId::new(0, 0, 0), Bytes::new())).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn nested_stage_failures_count_once() {
+        let (_, sink) = write_pipeline();
+        let publisher = Publisher::new(sink, false);
+        let metrics = Metrics::new(1);
+        let cancellation = Cancellation::new(Arc::new(ConnInfo::new(None)), None);
+        let ctx = Context::new(ConnId::new(0, 0, 0), &cancellation, &publisher, &metrics);
+
+        let res: Result<()> = timed(&ctx, Stage::Process, || timed(&ctx, Stage::Serialize, || Err("bad".into())));
+        assert!(res.is_err());
+        assert_eq!(metrics.errors(Failure::Serialize), 1);
+        assert_eq!(metrics.errors(Failure::Process), 0);
+    }
+}